@@ -0,0 +1,54 @@
+// Times scanning generated scripts of doubling size, scanning time should double along with them.
+// Run with `cargo bench --bench scanner`.
+
+use std::{hint::black_box, time::{Duration, Instant}};
+
+use rlox::scanner::{Scanner, TokenType};
+
+const SNIPPET: &str = "
+struct Point {
+    x = 0;
+    y = 0;
+    fn length() { return self.x * self.x + self.y * self.y; }
+}
+let point = Point();
+let label = \"point é\";  # A comment
+for (let i = 0; i < 10.5; i = i + 1) { point.x = point.x + i; }
+";
+
+fn scan_all(source: &str) -> usize {
+    let mut scanner = Scanner::new(source);
+    let mut tokens = 0;
+    while scanner.scan_token().expect("Benchmark source should scan.").t_type != TokenType::Eof {
+        tokens += 1;
+    }
+    tokens
+}
+
+// Best of a few runs, to keep noise from other processes out of the comparison.
+fn time(source: &str) -> Duration {
+    (0..5).map(|_| {
+        let start = Instant::now();
+        black_box(scan_all(black_box(source)));
+        start.elapsed()
+    }).min().unwrap()
+}
+
+fn main() {
+    println!("{:>10} {:>10} {:>12} {:>10}", "bytes", "tokens", "time", "ns/byte");
+
+    let mut previous: Option<Duration> = None;
+    for kb in [100, 200, 400, 800, 1600] {
+        let source = SNIPPET.repeat(kb * 1024 / SNIPPET.len());
+        let elapsed = time(&source);
+        let ratio = previous.map_or(String::new(), |p| format!("  x{:.2}", elapsed.as_secs_f64() / p.as_secs_f64()));
+
+        println!("{:>10} {:>10} {:>12.2?} {:>10.2}{}",
+            source.len(),
+            scan_all(&source),
+            elapsed,
+            elapsed.as_nanos() as f64 / source.len() as f64,
+            ratio);
+        previous = Some(elapsed);
+    }
+}
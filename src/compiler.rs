@@ -4,10 +4,12 @@ use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 use crate::{
+    error::{CompileErrorKind, Error, Span},
     scanner::{
-        Scanner, Token, TokenType
+        Scanner, Token, TokenType, unescape
     }, vm::{Chunk, Op},
-    value::Value,
+    heap::{Heap, Obj},
+    value::{Function, UpvalueDesc, Value},
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, EnumIter)]
@@ -30,15 +32,86 @@ fn next_precedence(p: Precedence) -> Option<Precedence> {
     iter.skip_while(|&pr| pr != p).nth(1)
 }
 
+// A local variable living in a stack slot.
+// depth is None while the variable's initializer is being compiled.
+struct Local<'a> {
+    name:  &'a str,
+    depth: Option<usize>,
+    is_captured: bool, // Captured locals are moved off the stack by CloseUpvalue when they go out of scope
+}
+
+// Bookkeeping for the innermost loop being compiled, used by break and continue.
+struct Loop {
+    start:       usize, // Op continue jumps back to
+    scope_depth: usize, // Locals deeper than this belong to the loop body
+    breaks:      Vec<usize>,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum FunctionKind {
+    Script,
+    Function,
+    Method,
+    Initializer, // A struct's init method, which always returns self
+}
+
+// Bookkeeping for the innermost struct declaration being compiled.
+struct StructState {
+    has_parent: bool,
+}
+
+// Compilation state of a single function body.
+// Nested fn declarations push a new state, so each function gets its own chunk and locals.
+struct FunctionState<'a> {
+    function: Function,
+    kind:     FunctionKind,
+    variable_lut: HashMap<&'a str, usize>,
+    locals:   Vec<Local<'a>>,
+    scope_depth: usize,
+    loops:    Vec<Loop>,
+}
+
+impl<'a> FunctionState<'a> {
+    fn new(kind: FunctionKind, name: Option<&str>) -> Self {
+        Self {
+            function: Function::new(name),
+            kind,
+            variable_lut: HashMap::new(),
+            // Slot 0 holds the function being called, or the receiver in methods.
+            locals: vec![Local {
+                name: if matches!(kind, FunctionKind::Method | FunctionKind::Initializer) { "self" } else { "" },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            scope_depth: 0,
+            loops: Vec::new(),
+        }
+    }
+}
+
 pub struct Compiler<'a> {
-    chunk:    &'a mut Chunk,
     scanner:  Scanner<'a>,
-    variable_lut: HashMap<&'a str, usize>,
+    states:   Vec<FunctionState<'a>>,
+    structs:  Vec<StructState>,
     previous: Option<Token<'a>>,
     current:  Option<Token<'a>>,
+    heap:     &'a mut Heap, // Constants are allocated here, nothing is collected while compiling
+    errors:   Vec<Error>,
+    // Set by compile for each declaration directly in the script, and taken by the first one to start.
+    // Only such a statement's expression can be the script's result.
+    script_statement: bool,
 }
 
-type ParseFn<'a> = fn(&mut Compiler<'a>, Precedence) -> Result<(), String>;
+// Where to unwind the compiler's bookkeeping to after an error abandons a declaration part way through.
+struct Checkpoint {
+    states:  usize,
+    structs: usize,
+    scope_depth: usize,
+    locals:  usize,
+    loops:   usize,
+}
+
+type ParseFn<'a> = fn(&mut Compiler<'a>, Precedence) -> Result<(), Error>;
 struct ParseRule<'a> {
     prefix: Option<ParseFn<'a>>,
     infix:  Option<ParseFn<'a>>,
@@ -68,7 +141,8 @@ struct ParseRule<'a> {
     fn get(t: TokenType) -> Self {
         match t {
             // Syntax
-            TokenType::LParen => ParseRule::new(Some(Compiler::grouping), None,                   Precedence::None),
+            TokenType::LParen => ParseRule::new(Some(Compiler::grouping), Some(Compiler::call),   Precedence::Call),
+            TokenType::Dot    => ParseRule::new(None,                     Some(Compiler::dot),    Precedence::Call),
             
             // Operations
             TokenType::Minus       => ParseRule::new(Some(Compiler::unary),    Some(Compiler::binary), Precedence::Term),
@@ -82,14 +156,17 @@ struct ParseRule<'a> {
             TokenType::GreaterEq   => ParseRule::new(None,                     Some(Compiler::binary), Precedence::Comparison),
             TokenType::LessThan    => ParseRule::new(None,                     Some(Compiler::binary), Precedence::Comparison),
             TokenType::LessEq      => ParseRule::new(None,                     Some(Compiler::binary), Precedence::Comparison),
-            TokenType::And         => ParseRule::new(None,                     Some(Compiler::binary), Precedence::And),
-            TokenType::Or          => ParseRule::new(None,                     Some(Compiler::binary), Precedence::Or),
+            TokenType::And         => ParseRule::new(None,                     Some(Compiler::and),    Precedence::And),
+            TokenType::Or          => ParseRule::new(None,                     Some(Compiler::or),     Precedence::Or),
 
             TokenType::Identifier  => ParseRule::new(Some(Compiler::variable), None,                   Precedence::None),
+            TokenType::StructSelf  => ParseRule::new(Some(Compiler::self_),    None,                   Precedence::None),
+            TokenType::Super       => ParseRule::new(Some(Compiler::super_),   None,                   Precedence::None),
 
             // Literals
             TokenType::Number => ParseRule::new(Some(Compiler::number),   None,                   Precedence::None),
             TokenType::Str    => ParseRule::new(Some(Compiler::string),   None,                   Precedence::None),
+            TokenType::Interpolation => ParseRule::new(Some(Compiler::interpolation), None,       Precedence::None),
             TokenType::True |
             TokenType::False =>  ParseRule::new(Some(Compiler::literal),  None,                   Precedence::None),
             TokenType::Nil   =>  ParseRule::new(Some(Compiler::literal),  None,                   Precedence::None),
@@ -100,43 +177,129 @@ struct ParseRule<'a> {
 }
 
 impl<'a> Compiler<'a> {
-    pub fn new(chunk: &'a mut Chunk, source: &'a str) -> Self {
+    pub fn new(source: &'a str, heap: &'a mut Heap) -> Self {
         Self {
-            chunk,
             scanner: Scanner::new(source),
-            variable_lut: HashMap::new(),
+            states: vec![FunctionState::new(FunctionKind::Script, None)],
+            structs: Vec::new(),
             previous: None,
             current: None,
+            heap,
+            errors: Vec::new(),
+            script_statement: false,
         }
     }
 
+    fn state(&self) -> &FunctionState<'a> {
+        self.states.last().expect("Expected a function being compiled.")
+    }
+
+    fn state_mut(&mut self) -> &mut FunctionState<'a> {
+        self.states.last_mut().expect("Expected a function being compiled.")
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    // Span of the token just consumed, which ops are attributed to by default.
+    fn span(&self) -> Span {
+        self.previous.map_or(Span::default(), |t| t.span)
+    }
+
     fn emit_constant(&mut self, value: Value) {
-        self.chunk.push_constant(value, self.scanner.line);
+        let span = self.span();
+        self.chunk().push_constant(value, span);
     }
 
     fn emit_op(&mut self, op: Op) {
-        self.chunk.push_operation(op, self.scanner.line);
+        let span = self.span();
+        self.emit_op_at(op, span);
     } 
 
-    fn consume(&mut self) -> Result<(), String> {
-        self.previous = self.current;
-        if let Some(token) = self.scanner.scan_token() {
-            self.current = Some(token);
-            Ok(())
+    // Emits an op attributed to an earlier token, e.g. the operator of a binary expression.
+    fn emit_op_at(&mut self, op: Op, span: Span) {
+        self.chunk().push_operation(op, span);
+    }
+
+    fn emit_jump(&mut self, op: Op) -> usize {
+        let span = self.span();
+        self.chunk().push_jump(op, span)
+    }
+
+    fn emit_loop(&mut self, start: usize) {
+        let span = self.span();
+        self.chunk().push_loop(start, span);
+    }
+
+    fn emit_return(&mut self) {
+        if self.state().kind == FunctionKind::Initializer {
+            self.emit_op(Op::GetLocal(0));
         } else {
-            Err(self.scanner.err.clone())
+            self.emit_op(Op::Nil);
+        }
+        self.emit_op(Op::Return);
+    }
+
+    // Finishes the function being compiled, returning it along with its compilation state.
+    fn end_function(&mut self) -> FunctionState<'a> {
+        if self.state().kind != FunctionKind::Script {
+            self.emit_return();
+        }
+        self.states.pop().expect("Expected a function being compiled.")
+    }
+
+    // Moves on to the next token, skipping anything that can't be scanned.
+    // Fails with the first lex error, any further ones are recorded directly.
+    fn consume(&mut self) -> Result<(), Error> {
+        self.previous = self.current;
+        let mut error = None;
+        loop {
+            match self.scanner.scan_token() {
+                Ok(token) => {
+                    self.current = Some(token);
+                    break;
+                }
+                Err(e) if error.is_none() => error = Some(e),
+                Err(e) => self.errors.push(e),
+            }
         }
+        error.map_or(Ok(()), Err)
+    }
+
+    fn check(&self, t: TokenType) -> bool {
+        self.current.unwrap().t_type == t
     }
 
-    fn match_and_consume(&mut self, t: TokenType) -> Result<bool, String> {
+    fn match_and_consume(&mut self, t: TokenType) -> Result<bool, Error> {
         if self.current.unwrap().t_type == t {
             if let Err(err) = self.consume() {
                 Err(err)
             } else { Ok(true) }
         } else { Ok(false) }
     }
+
+    // Consumes a token of type t, or fails expecting what.
+    fn expect(&mut self, t: TokenType, what: &'static str) -> Result<(), Error> {
+        if self.match_and_consume(t)? { Ok(()) }
+        else { Err(self.error_at_current(CompileErrorKind::Expected(what))) }
+    }
+
+    fn error_at(token: Option<Token<'a>>, kind: CompileErrorKind) -> Error {
+        Error::Compile { kind, span: token.map_or(Span::default(), |t| t.span) }
+    }
+
+    // An error at the token just consumed.
+    fn error(&self, kind: CompileErrorKind) -> Error {
+        Self::error_at(self.previous, kind)
+    }
+
+    // An error at the token about to be consumed.
+    fn error_at_current(&self, kind: CompileErrorKind) -> Error {
+        Self::error_at(self.current, kind)
+    }
     
-    fn parse_precedence(&mut self, p: Precedence) -> Result<(), String> {
+    fn parse_precedence(&mut self, p: Precedence) -> Result<(), Error> {
         self.consume()?;
         // Expect first token to have a prefix expression, if not compiler error.
         let rule = ParseRule::get(self.previous.unwrap().t_type);
@@ -154,152 +317,556 @@ impl<'a> Compiler<'a> {
             }
             
             if p <= Precedence::Assignment && self.match_and_consume(TokenType::Equal)? {
-                Err("invalid assignment target.".to_string())?;
+                return Err(self.error(CompileErrorKind::InvalidAssignmentTarget));
             }
 
             Ok(())
         } else {
-            Err("expected expression.".to_string())
+            Err(self.error(CompileErrorKind::ExpectedExpression))
         }
     }
 
-    fn expression(&mut self) -> Result<(), String> {
+    fn expression(&mut self) -> Result<(), Error> {
         self.parse_precedence(Precedence::Assignment)
     }
 
-    fn string(&mut self, _: Precedence) -> Result<(), String> {
-        if let Some(value) = self.previous.unwrap().slice
-            .strip_prefix('"')
-            .and_then(|s| s.strip_suffix('"'))
-        {
-            self.emit_constant(Value::from_str(value));
-            Ok(())
+    fn string(&mut self, _: Precedence) -> Result<(), Error> {
+        let slice = self.previous.unwrap().slice;
+        let value = if let Some(raw) = slice.strip_prefix('r') {
+            let hashes = raw.len() - raw.trim_start_matches('#').len();
+            raw[hashes + 1..raw.len() - hashes - 1].to_string()
         } else {
-            Err("expected a string literal.".to_string())
+            unescape(&slice[1..slice.len() - 1])
+        };
+
+        let value = Value::Str(self.heap.alloc_str(&value));
+        self.emit_constant(value);
+        Ok(())
+    }
+
+    // "a${x}b${y}c" is lowered to "a" + str(x) + "b" + str(y) + "c", dropping empty segments.
+    fn interpolation(&mut self, _: Precedence) -> Result<(), Error> {
+        // Segments are the token slice without the '"' or '}' before it and the '${' or '"' after it
+        let slice = self.previous.unwrap().slice;
+        let value = Value::Str(self.heap.alloc_str(&unescape(&slice[1..slice.len() - 2])));
+        self.emit_constant(value);
+
+        loop {
+            self.expression()?;
+            self.emit_op(Op::ToStr);
+            self.emit_op(Op::Add);
+
+            let end = !self.match_and_consume(TokenType::Interpolation)?;
+            if end {
+                self.expect(TokenType::InterpolationEnd, "'}' after interpolated expression")?;
+            }
+            let slice = self.previous.unwrap().slice;
+            let segment = unescape(&slice[1..slice.len() - if end { 1 } else { 2 }]);
+            if !segment.is_empty() {
+                let value = Value::Str(self.heap.alloc_str(&segment));
+                self.emit_constant(value);
+                self.emit_op(Op::Add);
+            }
+            if end { return Ok(()); }
         }
     }
 
-    fn number(&mut self, _: Precedence) -> Result<(), String> {
-        if let Ok(value) = self.previous.unwrap().slice.parse() {
-            self.emit_constant(Value::Number(value));
-            Ok(())
-        } else {
-            Err("expected a number literal.".to_string())
+    fn number(&mut self, _: Precedence) -> Result<(), Error> {
+        match parse_number(self.previous.unwrap().slice) {
+            Ok(value) => {
+                self.emit_constant(Value::Number(value));
+                Ok(())
+            }
+            Err(kind) => Err(self.error(kind)),
         }
     }
 
-    fn literal(&mut self, _: Precedence) -> Result<(), String> {
+    fn literal(&mut self, _: Precedence) -> Result<(), Error> {
         match self.previous.unwrap().t_type {
             TokenType::False => self.emit_op(Op::False),
             TokenType::True  => self.emit_op(Op::True),
             TokenType::Nil   => self.emit_op(Op::Nil),
-            _ => return Err("invalid literal expression.".to_string()),
+            _ => unreachable!("invalid literal expression."),
         }
         Ok(())
     }
 
-    fn unary(&mut self, _: Precedence) -> Result<(), String> {
+    fn unary(&mut self, _: Precedence) -> Result<(), Error> {
         let op_type = self.previous.unwrap().t_type;
+        let span = self.span();
         
         self.parse_precedence(Precedence::Unary)?;
 
         match op_type {
-            TokenType::Minus => self.emit_op(Op::Negate),
-            TokenType::Bang  => self.emit_op(Op::Not),
-            _ => return Err("invalid unary operation.".to_string()),
+            TokenType::Minus => self.emit_op_at(Op::Negate, span),
+            TokenType::Bang  => self.emit_op_at(Op::Not, span),
+            _ => unreachable!("invalid unary operation."),
         }
 
         Ok(())
     }
     
-    fn binary(&mut self, _: Precedence) -> Result<(), String> {
+    fn binary(&mut self, _: Precedence) -> Result<(), Error> {
         let op_type = self.previous.unwrap().t_type;
+        let span = self.span();
         
         let rule = ParseRule::get(op_type);
         self.parse_precedence(
             next_precedence(rule.precedence)
-                .unwrap_or(Precedence::iter().last().unwrap())
+                .unwrap_or(Precedence::iter().next_back().unwrap())
         )?;
         
         match op_type {
-            TokenType::Plus => self.emit_op(Op::Add),
-            TokenType::Minus => self.emit_op(Op::Sub),
-            TokenType::Star => self.emit_op(Op::Mul),
-            TokenType::Slash => self.emit_op(Op::Div),
-            TokenType::Equate      => self.emit_op(Op::Equal),
-            TokenType::BangEqual   => self.emit_op(Op::NotEqual),
-            TokenType::GreaterThan => self.emit_op(Op::GreaterThan),
-            TokenType::GreaterEq   => self.emit_op(Op::GreaterEq),
-            TokenType::LessThan    => self.emit_op(Op::LessThan),
-            TokenType::LessEq      => self.emit_op(Op::LessEq),
-            TokenType::And         => self.emit_op(Op::And),
-            TokenType::Or          => self.emit_op(Op::Or),
-            _ => return Err("invalid operand for binary expression.".to_string()),
+            TokenType::Plus => self.emit_op_at(Op::Add, span),
+            TokenType::Minus => self.emit_op_at(Op::Sub, span),
+            TokenType::Star => self.emit_op_at(Op::Mul, span),
+            TokenType::Slash => self.emit_op_at(Op::Div, span),
+            TokenType::Equate      => self.emit_op_at(Op::Equal, span),
+            TokenType::BangEqual   => self.emit_op_at(Op::NotEqual, span),
+            TokenType::GreaterThan => self.emit_op_at(Op::GreaterThan, span),
+            TokenType::GreaterEq   => self.emit_op_at(Op::GreaterEq, span),
+            TokenType::LessThan    => self.emit_op_at(Op::LessThan, span),
+            TokenType::LessEq      => self.emit_op_at(Op::LessEq, span),
+            _ => unreachable!("invalid operand for binary expression."),
         }
 
         Ok(())
     }
 
-    fn grouping(&mut self, _: Precedence) -> Result<(), String> {
-        if let Err(e) = self.expression() {
-            return Err(e);
-        }
+    // Skips the right operand when the left is falsey, leaving the left as the result.
+    fn and(&mut self, _: Precedence) -> Result<(), Error> {
+        let end_jump = self.emit_jump(Op::JumpIfFalse(0));
+        self.emit_op(Op::Pop);
+        self.parse_precedence(next_precedence(Precedence::And).unwrap())?;
+        self.chunk().patch_jump(end_jump);
+
+        Ok(())
+    }
+
+    // Skips the right operand when the left is truthy, leaving the left as the result.
+    fn or(&mut self, _: Precedence) -> Result<(), Error> {
+        let else_jump = self.emit_jump(Op::JumpIfFalse(0));
+        let end_jump = self.emit_jump(Op::Jump(0));
+
+        self.chunk().patch_jump(else_jump);
+        self.emit_op(Op::Pop);
+        self.parse_precedence(next_precedence(Precedence::Or).unwrap())?;
+        self.chunk().patch_jump(end_jump);
+
+        Ok(())
+    }
+
+    fn grouping(&mut self, _: Precedence) -> Result<(), Error> {
+        self.expression()?;
 
         match self.match_and_consume(TokenType::RParen) {
             Ok(result) => if result { Ok(()) }
-                          else      { Err("expected ')' after expression.".to_string()) }
+                          else      { Err(self.error_at_current(CompileErrorKind::Expected("')' after expression"))) }
             Err(e) => Err(e),
         }
     }
 
-    fn identifier_constant(&mut self) -> usize {
-        let name = self.previous.unwrap().slice;
-        if let Some(global) = self.variable_lut.get(name) {
+    fn call(&mut self, _: Precedence) -> Result<(), Error> {
+        let span = self.span();
+        let argc = self.argument_list()?;
+        self.emit_op_at(Op::Call(argc), span);
+        Ok(())
+    }
+
+    fn argument_list(&mut self) -> Result<usize, Error> {
+        let mut argc = 0;
+        if !self.check(TokenType::RParen) {
+            loop {
+                self.expression()?;
+                argc += 1;
+                if !self.match_and_consume(TokenType::Comma)? { break }
+            }
+        }
+        self.expect(TokenType::RParen, "')' after arguments")?;
+        Ok(argc)
+    }
+
+    fn dot(&mut self, p: Precedence) -> Result<(), Error> {
+        self.expect(TokenType::Identifier, "property name after '.'")?;
+        let name = self.identifier_constant(self.previous.unwrap().slice);
+        let span = self.span();
+
+        if p <= Precedence::Assignment && self.match_and_consume(TokenType::Equal)? {
+            self.expression()?;
+            self.emit_op_at(Op::SetProperty(name), span);
+        } else if self.match_and_consume(TokenType::LParen)? {
+            // Calling a method directly skips creating a bound method.
+            let argc = self.argument_list()?;
+            self.emit_op_at(Op::Invoke(name, argc), span);
+        } else {
+            self.emit_op(Op::GetProperty(name));
+        }
+        Ok(())
+    }
+
+    fn self_(&mut self, _: Precedence) -> Result<(), Error> {
+        if self.structs.is_empty() {
+            return Err(self.error(CompileErrorKind::SelfOutsideStruct));
+        }
+        self.named_variable("self", false)
+    }
+
+    fn super_(&mut self, _: Precedence) -> Result<(), Error> {
+        match self.structs.last() {
+            None => return Err(self.error(CompileErrorKind::SuperOutsideStruct)),
+            Some(s) if !s.has_parent => return Err(self.error(CompileErrorKind::SuperWithoutParent)),
+            _ => (),
+        }
+
+        self.expect(TokenType::Dot, "'.' after 'super'")?;
+        self.expect(TokenType::Identifier, "parent method name")?;
+        let name = self.identifier_constant(self.previous.unwrap().slice);
+        let span = self.span();
+
+        self.named_variable("self", false)?;
+        if self.match_and_consume(TokenType::LParen)? {
+            let argc = self.argument_list()?;
+            self.named_variable("super", false)?;
+            self.emit_op_at(Op::SuperInvoke(name, argc), span);
+        } else {
+            self.named_variable("super", false)?;
+            self.emit_op(Op::GetSuper(name));
+        }
+        Ok(())
+    }
+
+    fn identifier_constant(&mut self, name: &'a str) -> usize {
+        if let Some(global) = self.state().variable_lut.get(name) {
             *global
         } else {
-            let global = self.chunk.add_constant(Value::from_str(self.previous.unwrap().slice));
-            self.variable_lut.insert(name, global);
+            let name_ref = self.heap.alloc_str(name);
+            let global = self.chunk().add_constant(Value::Str(name_ref));
+            self.state_mut().variable_lut.insert(name, global);
             global
         }
     }
 
-    fn named_variable(&mut self, can_assign: bool) -> Result<(), String> {
-        let global = self.identifier_constant();
+    // Finds the stack slot of a local variable, searching innermost scopes first.
+    fn resolve_local(&self, state: usize, name: &str) -> Result<Option<usize>, Error> {
+        for (slot, local) in self.states[state].locals.iter().enumerate().rev() {
+            if local.name == name {
+                return if local.depth.is_some() { Ok(Some(slot)) }
+                       else { Err(self.error(CompileErrorKind::ReadInOwnInitializer)) }
+            }
+        }
+        Ok(None)
+    }
+
+    // Resolves a variable from an enclosing function, threading it through every function in between.
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Result<Option<usize>, Error> {
+        if state == 0 { return Ok(None) }
+        let enclosing = state - 1;
+
+        if let Some(slot) = self.resolve_local(enclosing, name)? {
+            self.states[enclosing].locals[slot].is_captured = true;
+            return Ok(Some(self.add_upvalue(state, UpvalueDesc { is_local: true, index: slot })));
+        }
+        if let Some(index) = self.resolve_upvalue(enclosing, name)? {
+            return Ok(Some(self.add_upvalue(state, UpvalueDesc { is_local: false, index })));
+        }
+        Ok(None)
+    }
+
+    fn add_upvalue(&mut self, state: usize, upvalue: UpvalueDesc) -> usize {
+        let upvalues = &mut self.states[state].function.upvalues;
+        if let Some(idx) = upvalues.iter().position(|u| *u == upvalue) {
+            return idx;
+        }
+        upvalues.push(upvalue);
+        upvalues.len() - 1
+    }
+
+    fn named_variable(&mut self, name: &'a str, can_assign: bool) -> Result<(), Error> {
+        let state = self.states.len() - 1;
+        let (get_op, set_op) = if let Some(slot) = self.resolve_local(state, name)? {
+            (Op::GetLocal(slot), Op::SetLocal(slot))
+        } else if let Some(idx) = self.resolve_upvalue(state, name)? {
+            (Op::GetUpvalue(idx), Op::SetUpvalue(idx))
+        } else {
+            let global = self.identifier_constant(name);
+            (Op::GetGlobal(global), Op::SetGlobal(global))
+        };
+
+        let span = self.span();
         if can_assign && self.match_and_consume(TokenType::Equal)? {
             self.expression()?;
-            self.emit_op(Op::SetGlobal(global));
+            self.emit_op_at(set_op, span);
         } else {
-            self.emit_op(Op::GetGlobal(global));
+            self.emit_op(get_op);
         }
 
         Ok(())
     }
 
-    fn variable(&mut self, p: Precedence) -> Result<(), String> {
-        self.named_variable(p <= Precedence::Assignment)?;
-        Ok(())
+    fn variable(&mut self, p: Precedence) -> Result<(), Error> {
+        self.named_variable(self.previous.unwrap().slice, p <= Precedence::Assignment)
     }
 
-    fn parse_variable(&mut self, e: &str) -> Result<usize, String> {
+    // Consumes a variable name, returning its constant index when it is a global.
+    fn parse_variable(&mut self, what: &'static str) -> Result<usize, Error> {
         if self.match_and_consume(TokenType::Identifier)? {
-            Ok(self.identifier_constant())
-        } else { Err(e.to_string()) }
+            self.declare_variable()?;
+            if self.state().scope_depth > 0 { return Ok(0) }
+            Ok(self.identifier_constant(self.previous.unwrap().slice))
+        } else { Err(self.error_at_current(CompileErrorKind::Expected(what))) }
     }
 
-    fn declaration(&mut self) -> Result<(), String> {
-        let result = if self.match_and_consume(TokenType::Let)? {
+    // Adds the previous identifier to the current scope as an uninitialized local.
+    fn declare_variable(&mut self) -> Result<(), Error> {
+        let depth = self.state().scope_depth;
+        if depth == 0 { return Ok(()) }
+
+        let name = self.previous.unwrap().slice;
+        for local in self.state().locals.iter().rev() {
+            if local.depth.is_some_and(|d| d < depth) { break }
+            if local.name == name {
+                return Err(self.error(CompileErrorKind::AlreadyDeclared(name.to_string())));
+            }
+        }
+
+        self.state_mut().locals.push(Local { name, depth: None, is_captured: false });
+        Ok(())
+    }
+
+    // Marks the most recently declared local as usable.
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 { return }
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+        } else {
+            self.emit_op(Op::DefineGlobal(global));
+        }
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
+    }
+
+    // Pops every local declared in the scope being closed.
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+        self.discard_locals(self.state().scope_depth);
+
+        let depth = self.state().scope_depth;
+        let locals = &mut self.state_mut().locals;
+        while locals.last().is_some_and(|l| l.depth.is_none_or(|d| d > depth)) {
+            locals.pop();
+        }
+    }
+
+    // Pops locals deeper than depth off the stack without ending their scope, for jumps out of a block.
+    fn discard_locals(&mut self, depth: usize) {
+        let ops: Vec<Op> = self.state().locals.iter().rev()
+            .take_while(|l| l.depth.is_none_or(|d| d > depth))
+            .map(|l| if l.is_captured { Op::CloseUpvalue } else { Op::Pop })
+            .collect();
+        for op in ops {
+            self.emit_op(op);
+        }
+    }
+
+    fn checkpoint(&self) -> Checkpoint {
+        Checkpoint {
+            states:  self.states.len(),
+            structs: self.structs.len(),
+            scope_depth: self.state().scope_depth,
+            locals:  self.state().locals.len(),
+            loops:   self.state().loops.len(),
+        }
+    }
+
+    fn restore(&mut self, checkpoint: Checkpoint) {
+        self.states.truncate(checkpoint.states);
+        self.structs.truncate(checkpoint.structs);
+        let state = self.state_mut();
+        state.scope_depth = checkpoint.scope_depth;
+        state.locals.truncate(checkpoint.locals);
+        state.loops.truncate(checkpoint.loops);
+    }
+
+    // Skips tokens until a likely statement boundary, so one mistake doesn't cause a cascade of errors.
+    fn synchronize(&mut self) {
+        while !self.check(TokenType::Eof) {
+            if self.previous.is_some_and(|t| t.t_type == TokenType::Semicolon) { return }
+            match self.current.unwrap().t_type {
+                TokenType::Let    | TokenType::Fn     | TokenType::Struct |
+                TokenType::If     | TokenType::While  | TokenType::For    |
+                TokenType::Print  | TokenType::Return => return,
+                _ => (),
+            }
+            if let Err(e) = self.consume() {
+                self.errors.push(e);
+            }
+        }
+    }
+
+    // Compiles a declaration, recording any error and recovering so compilation can carry on past it.
+    fn declaration_or_recover(&mut self) {
+        let checkpoint = self.checkpoint();
+        if let Err(e) = self.declaration() {
+            self.errors.push(e);
+            self.restore(checkpoint);
+            self.synchronize();
+        }
+    }
+
+    fn declaration(&mut self) -> Result<(), Error> {
+        let script_statement = std::mem::take(&mut self.script_statement);
+        if self.match_and_consume(TokenType::Let)? {
             self.let_declaration()
+        } else if self.match_and_consume(TokenType::Fn)? {
+            self.fn_declaration()
+        } else if self.match_and_consume(TokenType::Struct)? {
+            self.struct_declaration()
         } else {
+            self.script_statement = script_statement;
             self.statement()
-        };
+        }
+    }
+
+    fn struct_declaration(&mut self) -> Result<(), Error> {
+        self.expect(TokenType::Identifier, "struct name")?;
+        let name = self.previous.unwrap().slice;
+        let name_constant = self.identifier_constant(name);
+        self.declare_variable()?;
+
+        self.emit_op(Op::Struct(name_constant));
+        self.define_variable(name_constant);
+
+        self.structs.push(StructState { has_parent: false });
+        let result = self.struct_parent(name).and_then(|_| self.struct_body(name));
+        let state = self.structs.pop().unwrap();
+        result?;
+
+        if state.has_parent {
+            self.end_scope();
+        }
+        Ok(())
+    }
+
+    // Compiles an optional '< Parent' clause, copying the parent's fields and methods into the struct.
+    // The parent is kept in a 'super' local scoped to the struct body.
+    fn struct_parent(&mut self, name: &'a str) -> Result<(), Error> {
+        if !self.match_and_consume(TokenType::LessThan)? { return Ok(()) }
+
+        self.expect(TokenType::Identifier, "parent struct name")?;
+        let parent = self.previous.unwrap().slice;
+        if parent == name {
+            return Err(self.error(CompileErrorKind::InheritFromSelf));
+        }
+        self.named_variable(parent, false)?;
 
-        if let Err(e) = result {
-            Err(format!("Compile Error: at line {}: {}", self.scanner.line, e))
-        } else { Ok(()) }
+        self.begin_scope();
+        self.state_mut().locals.push(Local { name: "super", depth: None, is_captured: false });
+        self.mark_initialized();
+        self.structs.last_mut().unwrap().has_parent = true;
+
+        self.named_variable(name, false)?;
+        self.emit_op(Op::Inherit);
+        Ok(())
     }
 
-    fn let_declaration(&mut self) -> Result<(), String> {
-        let global = self.parse_variable("expected variable name.")?;
+    // Compiles fields and methods onto the struct, which is kept on the stack while they are added.
+    fn struct_body(&mut self, name: &'a str) -> Result<(), Error> {
+        self.named_variable(name, false)?;
+        self.expect(TokenType::LBrace, "'{' before struct body")?;
+        while !self.check(TokenType::RBrace) && !self.check(TokenType::Eof) {
+            if self.match_and_consume(TokenType::Fn)? {
+                self.method()?;
+            } else {
+                self.field()?;
+            }
+        }
+        self.expect(TokenType::RBrace, "'}' after struct body")?;
+        self.emit_op(Op::Pop);
+        Ok(())
+    }
+
+    // A field's default is compiled into a method run on each new instance, so instances don't share objects.
+    // It ends by storing its value into the instance rather than returning it.
+    fn field(&mut self) -> Result<(), Error> {
+        self.expect(TokenType::Identifier, "field or method declaration")?;
+        let field = self.previous.unwrap().slice;
+        let name = self.identifier_constant(field);
+
+        if self.match_and_consume(TokenType::Equal)? {
+            self.states.push(FunctionState::new(FunctionKind::Method, Some(field)));
+            self.expression()?;
+            let constant = self.identifier_constant(field);
+            self.emit_op(Op::InitField(constant));
+
+            let state = self.states.pop().expect("Expected a function being compiled.");
+            let function = self.heap.alloc(Obj::Function(state.function));
+            let constant = self.chunk().add_constant(Value::Function(function));
+            self.emit_op(Op::Closure(constant));
+        } else {
+            self.emit_op(Op::Nil);
+        }
+        self.expect(TokenType::Semicolon, "';' after field declaration")?;
+        self.emit_op(Op::Field(name));
+        Ok(())
+    }
+
+    fn method(&mut self) -> Result<(), Error> {
+        self.expect(TokenType::Identifier, "method name")?;
+        let name = self.previous.unwrap().slice;
+        let constant = self.identifier_constant(name);
+
+        let kind = if name == "init" { FunctionKind::Initializer } else { FunctionKind::Method };
+        self.function(kind)?;
+        self.emit_op(Op::Method(constant));
+        Ok(())
+    }
+
+    fn fn_declaration(&mut self) -> Result<(), Error> {
+        let global = self.parse_variable("function name")?;
+        // A local function may refer to itself, so it is usable before its body is compiled.
+        self.mark_initialized();
+        self.function(FunctionKind::Function)?;
+        self.define_variable(global);
+        Ok(())
+    }
+
+    // Compiles a parameter list and body into a new function, emitting it as a constant.
+    fn function(&mut self, kind: FunctionKind) -> Result<(), Error> {
+        let name = self.previous.unwrap().slice;
+        self.states.push(FunctionState::new(kind, Some(name)));
+        self.begin_scope();
+
+        self.expect(TokenType::LParen, "'(' after function name")?;
+        if !self.check(TokenType::RParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                let constant = self.parse_variable("parameter name")?;
+                self.define_variable(constant);
+                if !self.match_and_consume(TokenType::Comma)? { break }
+            }
+        }
+        self.expect(TokenType::RParen, "')' after parameters")?;
+        self.expect(TokenType::LBrace, "'{' before function body")?;
+        self.block()?;
+
+        let state = self.end_function();
+        let function = self.heap.alloc(Obj::Function(state.function));
+        let constant = self.chunk().add_constant(Value::Function(function));
+        self.emit_op(Op::Closure(constant));
+        Ok(())
+    }
+
+    fn let_declaration(&mut self) -> Result<(), Error> {
+        let global = self.parse_variable("variable name")?;
         if self.match_and_consume(TokenType::Equal)? {
             self.expression()?;
         } else {
@@ -307,55 +874,279 @@ impl<'a> Compiler<'a> {
         }
         
         if self.match_and_consume(TokenType::Semicolon)? {
-            self.emit_op(Op::DefineGlobal(global));
+            self.define_variable(global);
             Ok(())
         } else {
-            Err("expected ';' after variable declaration.".to_string())
+            Err(self.error_at_current(CompileErrorKind::Expected("';' after variable declaration")))
         }
     }
 
-    fn statement(&mut self) -> Result<(), String> {
+    fn statement(&mut self) -> Result<(), Error> {
+        // Taken first, so the bodies of if, while and the like are never the script's result
+        let script_statement = std::mem::take(&mut self.script_statement);
         if self.match_and_consume(TokenType::Print)? {
             self.print_statement()
+        } else if self.match_and_consume(TokenType::If)? {
+            self.if_statement()
+        } else if self.match_and_consume(TokenType::While)? {
+            self.while_statement()
+        } else if self.match_and_consume(TokenType::For)? {
+            self.for_statement()
+        } else if self.match_and_consume(TokenType::Return)? {
+            self.return_statement()
+        } else if self.match_and_consume(TokenType::Break)? {
+            self.break_statement()
+        } else if self.match_and_consume(TokenType::Continue)? {
+            self.continue_statement()
+        } else if self.match_and_consume(TokenType::LBrace)? {
+            self.begin_scope();
+            self.block()?;
+            self.end_scope();
+            Ok(())
+        } else {
+            self.expression_statement(script_statement)
+        }
+    }
+
+    fn block(&mut self) -> Result<(), Error> {
+        while !self.check(TokenType::RBrace) && !self.check(TokenType::Eof) {
+            self.declaration_or_recover();
+        }
+
+        if self.match_and_consume(TokenType::RBrace)? { Ok(()) }
+        else { Err(self.error_at_current(CompileErrorKind::Expected("'}' after block"))) }
+    }
+
+    fn if_statement(&mut self) -> Result<(), Error> {
+        self.expect(TokenType::LParen, "'(' after 'if'")?;
+        self.expression()?;
+        self.expect(TokenType::RParen, "')' after condition")?;
+
+        // The condition stays on the stack for JumpIfFalse, so each branch pops it.
+        let then_jump = self.emit_jump(Op::JumpIfFalse(0));
+        self.emit_op(Op::Pop);
+        self.statement()?;
+        let else_jump = self.emit_jump(Op::Jump(0));
+
+        self.chunk().patch_jump(then_jump);
+        self.emit_op(Op::Pop);
+        if self.match_and_consume(TokenType::Else)? {
+            self.statement()?;
+        }
+        self.chunk().patch_jump(else_jump);
+
+        Ok(())
+    }
+
+    // Compiles a loop body that jumps back to start, returning its breaks to be patched past the loop.
+    fn loop_body(&mut self, start: usize) -> Result<Vec<usize>, Error> {
+        let scope_depth = self.state().scope_depth;
+        self.state_mut().loops.push(Loop {
+            start,
+            scope_depth,
+            breaks: Vec::new(),
+        });
+        let result = self.statement();
+        let lp = self.state_mut().loops.pop().unwrap();
+        result?;
+
+        self.emit_loop(start);
+        Ok(lp.breaks)
+    }
+
+    fn while_statement(&mut self) -> Result<(), Error> {
+        let start = self.chunk().code.len();
+        self.expect(TokenType::LParen, "'(' after 'while'")?;
+        self.expression()?;
+        self.expect(TokenType::RParen, "')' after condition")?;
+
+        let exit_jump = self.emit_jump(Op::JumpIfFalse(0));
+        self.emit_op(Op::Pop);
+        let breaks = self.loop_body(start)?;
+
+        // Breaks land after the condition's Pop, as they leave with the condition already popped.
+        self.chunk().patch_jump(exit_jump);
+        self.emit_op(Op::Pop);
+        for jump in breaks {
+            self.chunk().patch_jump(jump);
+        }
+        Ok(())
+    }
+
+    fn for_statement(&mut self) -> Result<(), Error> {
+        self.begin_scope();
+        self.expect(TokenType::LParen, "'(' after 'for'")?;
+
+        // Initializer
+        if self.match_and_consume(TokenType::Let)? {
+            self.let_declaration()?;
+        } else if !self.match_and_consume(TokenType::Semicolon)? {
+            self.expression_statement(false)?;
+        }
+
+        // Condition
+        let mut start = self.chunk().code.len();
+        let mut exit_jump = None;
+        if !self.match_and_consume(TokenType::Semicolon)? {
+            self.expression()?;
+            self.expect(TokenType::Semicolon, "';' after loop condition")?;
+            exit_jump = Some(self.emit_jump(Op::JumpIfFalse(0)));
+            self.emit_op(Op::Pop);
+        }
+
+        // Increment, compiled before the body but run after it.
+        if !self.match_and_consume(TokenType::RParen)? {
+            let body_jump = self.emit_jump(Op::Jump(0));
+            let increment_start = self.chunk().code.len();
+            self.expression()?;
+            self.emit_op(Op::Pop);
+            self.expect(TokenType::RParen, "')' after for clauses")?;
+
+            self.emit_loop(start);
+            start = increment_start;
+            self.chunk().patch_jump(body_jump);
+        }
+
+        let breaks = self.loop_body(start)?;
+
+        if let Some(exit_jump) = exit_jump {
+            self.chunk().patch_jump(exit_jump);
+            self.emit_op(Op::Pop);
+        }
+        for jump in breaks {
+            self.chunk().patch_jump(jump);
+        }
+
+        self.end_scope();
+        Ok(())
+    }
+
+    fn return_statement(&mut self) -> Result<(), Error> {
+        if self.state().kind == FunctionKind::Script {
+            return Err(self.error(CompileErrorKind::ReturnFromTopLevel));
+        }
+
+        if self.match_and_consume(TokenType::Semicolon)? {
+            self.emit_return();
         } else {
-            self.expression_statement()
+            if self.state().kind == FunctionKind::Initializer {
+                return Err(self.error(CompileErrorKind::ReturnFromInitializer));
+            }
+            self.expression()?;
+            self.expect(TokenType::Semicolon, "';' after return value")?;
+            self.emit_op(Op::Return);
         }
+        Ok(())
     }
 
-    fn print_statement(&mut self) -> Result<(), String> {
+    fn break_statement(&mut self) -> Result<(), Error> {
+        let Some(depth) = self.state().loops.last().map(|l| l.scope_depth) else {
+            return Err(self.error(CompileErrorKind::BreakOutsideLoop));
+        };
+        self.expect(TokenType::Semicolon, "';' after 'break'")?;
+
+        self.discard_locals(depth);
+        let jump = self.emit_jump(Op::Jump(0));
+        self.state_mut().loops.last_mut().unwrap().breaks.push(jump);
+        Ok(())
+    }
+
+    fn continue_statement(&mut self) -> Result<(), Error> {
+        let Some((depth, start)) = self.state().loops.last().map(|l| (l.scope_depth, l.start)) else {
+            return Err(self.error(CompileErrorKind::ContinueOutsideLoop));
+        };
+        self.expect(TokenType::Semicolon, "';' after 'continue'")?;
+
+        self.discard_locals(depth);
+        self.emit_loop(start);
+        Ok(())
+    }
+
+    fn print_statement(&mut self) -> Result<(), Error> {
         self.expression()?;
         match self.match_and_consume(TokenType::Semicolon) {
             Ok(result) => if result { self.emit_op(Op::Print); Ok(()) }
-                          else      { Err("expected ';' after expression.".to_string()) }
+                          else      { Err(self.error_at_current(CompileErrorKind::Expected("';' after expression"))) }
             Err(e) => Err(e),
         }
     }
 
-    fn expression_statement(&mut self) -> Result<(), String> {
+    // An expression statement directly in the script and ending it is the script's result, and may leave off the ';'.
+    fn expression_statement(&mut self, script_statement: bool) -> Result<(), Error> {
         self.expression()?;
-        match self.match_and_consume(TokenType::Semicolon) {
-            Ok(result) => if result { self.emit_op(Op::Pop); Ok(()) }
-                          else      { Err("expected ';' after expression.".to_string()) }
-            Err(e) => Err(e),
+        let terminated = self.match_and_consume(TokenType::Semicolon)?;
+        let result = script_statement && self.check(TokenType::Eof);
+        if !terminated && !result {
+            return Err(self.error_at_current(CompileErrorKind::Expected("';' after expression")));
         }
+
+        self.emit_op(if result { Op::Return } else { Op::Pop });
+        Ok(())
     }
 }
 
-pub fn compile(source: &str) -> Result<Chunk, String> {
-    let mut chunk = Chunk::new();
-    let mut compiler  = Compiler::new(&mut chunk, source);
+// Compiles source into the top-level script's chunk, or every error found in it, in source order.
+pub fn compile(source: &str, heap: &mut Heap) -> Result<Chunk, Vec<Error>> {
+    let mut compiler  = Compiler::new(source, heap);
    
     // Pump the compiler.
     if let Err(e) = compiler.consume() {
-        return Err(format!("Compile Error: consuming first eppression: {}", e));
+        compiler.errors.push(e);
     }
     
     // Compile the source.
-    loop {
-        match compiler.match_and_consume(TokenType::Eof) {
-            Ok(result) => if !result { compiler.declaration()?; }
-                          else       { break Ok(dbg!(chunk)) }
-            Err(e) => break Err(e),
+    while !compiler.check(TokenType::Eof) {
+        compiler.script_statement = true;
+        compiler.declaration_or_recover();
+    }
+
+    let chunk = compiler.end_function().function.chunk;
+    if compiler.errors.is_empty() {
+        Ok(chunk)
+    } else {
+        compiler.errors.sort_by_key(|e| e.span().start);
+        Err(compiler.errors)
+    }
+}
+
+// Parses a number literal as scanned, e.g. 1_000, 0xFF, 0b1010, 0o755 or 1e-9.
+// Underscores may appear anywhere after the first digit or prefix.
+fn parse_number(literal: &str) -> Result<f64, CompileErrorKind> {
+    let radix = match literal.get(..2) {
+        Some("0x" | "0X") => 16,
+        Some("0o" | "0O") => 8,
+        Some("0b" | "0B") => 2,
+        _ => 10,
+    };
+
+    if radix != 10 {
+        let mut digits = literal[2..].chars().filter(|&c| c != '_').peekable();
+        if digits.peek().is_none() {
+            return Err(CompileErrorKind::MissingDigits(literal.to_string()));
+        }
+        // Accumulating in a float means overlong literals lose precision rather than overflowing
+        return digits.try_fold(0.0, |value, digit| match digit.to_digit(radix) {
+            Some(d) => Ok(value * radix as f64 + d as f64),
+            None => Err(CompileErrorKind::InvalidDigit { digit, radix }),
+        });
+    }
+
+    let (mantissa, exponent) = match literal.split_once(['e', 'E']) {
+        Some((mantissa, exponent)) => (mantissa, Some(exponent.strip_prefix(['+', '-']).unwrap_or(exponent))),
+        None => (literal, None),
+    };
+    let invalid = |c: char| !c.is_ascii_digit() && c != '_';
+    if let Some(digit) = mantissa.chars().find(|&c| invalid(c) && c != '.') {
+        return Err(CompileErrorKind::InvalidDigit { digit, radix });
+    }
+    if let Some(exponent) = exponent {
+        if let Some(digit) = exponent.chars().find(|&c| invalid(c)) {
+            return Err(CompileErrorKind::InvalidDigit { digit, radix });
+        }
+        if !exponent.chars().any(|c| c.is_ascii_digit()) {
+            return Err(CompileErrorKind::MissingExponent(literal.to_string()));
         }
     }
+
+    literal.replace('_', "").parse().map_err(|_| CompileErrorKind::InvalidNumber(literal.to_string()))
 }
@@ -0,0 +1,228 @@
+use std::{collections::HashMap, rc::Rc};
+
+use crate::{error::{Error, RuntimeErrorKind, Span}, heap::{Heap, Obj}, value::{NativeFn, Value}, vm::VM};
+
+// Conversion from a script value to a Rust type, failing with the type that was expected.
+// Borrowing conversions like &str live as long as the heap they were read from.
+pub trait FromValue<'a>: Sized {
+    fn from_value(value: Value, heap: &'a Heap) -> Result<Self, RuntimeErrorKind>;
+}
+
+// Conversion from a Rust type to a script value, allocating on the VM's heap if needed.
+pub trait IntoValue {
+    fn into_value(self, vm: &mut VM) -> Value;
+}
+
+fn mismatch(expected: &'static str, value: Value) -> RuntimeErrorKind {
+    RuntimeErrorKind::TypeMismatch { expected, got: value.type_name() }
+}
+
+impl FromValue<'_> for Value {
+    fn from_value(value: Value, _: &Heap) -> Result<Self, RuntimeErrorKind> {
+        Ok(value)
+    }
+}
+
+impl FromValue<'_> for f64 {
+    fn from_value(value: Value, _: &Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Number(n) => Ok(n),
+            v => Err(mismatch("Number", v)),
+        }
+    }
+}
+
+// Only numbers with no fractional part that fit in an i64 convert.
+impl FromValue<'_> for i64 {
+    fn from_value(value: Value, _: &Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Number(n) if n.fract() == 0.0 && n >= i64::MIN as f64 && n < i64::MAX as f64 => Ok(n as i64),
+            v => Err(mismatch("Integer", v)),
+        }
+    }
+}
+
+impl FromValue<'_> for bool {
+    fn from_value(value: Value, _: &Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Bool(b) => Ok(b),
+            v => Err(mismatch("Bool", v)),
+        }
+    }
+}
+
+impl FromValue<'_> for String {
+    fn from_value(value: Value, heap: &Heap) -> Result<Self, RuntimeErrorKind> {
+        <&str>::from_value(value, heap).map(str::to_string)
+    }
+}
+
+impl<'a> FromValue<'a> for &'a str {
+    fn from_value(value: Value, heap: &'a Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Str(s) => Ok(heap.string(s)),
+            v => Err(mismatch("Str", v)),
+        }
+    }
+}
+
+// nil converts to None.
+impl<'a, T: FromValue<'a>> FromValue<'a> for Option<T> {
+    fn from_value(value: Value, heap: &'a Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Nil => Ok(None),
+            v => T::from_value(v, heap).map(Some),
+        }
+    }
+}
+
+impl<'a, T: FromValue<'a>> FromValue<'a> for Vec<T> {
+    fn from_value(value: Value, heap: &'a Heap) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::List(l) => heap.list(l).iter().map(|&v| T::from_value(v, heap)).collect(),
+            v => Err(mismatch("List", v)),
+        }
+    }
+}
+
+// Instances convert too, as a map of their fields.
+impl<'a, T: FromValue<'a>> FromValue<'a> for HashMap<String, T> {
+    fn from_value(value: Value, heap: &'a Heap) -> Result<Self, RuntimeErrorKind> {
+        let entries = match value {
+            Value::Map(m) => heap.map(m),
+            Value::Instance(i) => &heap.instance(i).fields,
+            v => return Err(mismatch("Map", v)),
+        };
+        entries.iter()
+            .map(|(&k, &v)| Ok((heap.string(k).to_string(), T::from_value(v, heap)?)))
+            .collect()
+    }
+}
+
+impl IntoValue for Value {
+    fn into_value(self, _: &mut VM) -> Value {
+        self
+    }
+}
+
+impl IntoValue for () {
+    fn into_value(self, _: &mut VM) -> Value {
+        Value::Nil
+    }
+}
+
+impl IntoValue for f64 {
+    fn into_value(self, _: &mut VM) -> Value {
+        Value::Number(self)
+    }
+}
+
+impl IntoValue for i64 {
+    fn into_value(self, _: &mut VM) -> Value {
+        Value::Number(self as f64)
+    }
+}
+
+impl IntoValue for bool {
+    fn into_value(self, _: &mut VM) -> Value {
+        Value::Bool(self)
+    }
+}
+
+impl IntoValue for String {
+    fn into_value(self, vm: &mut VM) -> Value {
+        vm.alloc_str(&self)
+    }
+}
+
+impl IntoValue for &str {
+    fn into_value(self, vm: &mut VM) -> Value {
+        vm.alloc_str(self)
+    }
+}
+
+impl<T: IntoValue> IntoValue for Option<T> {
+    fn into_value(self, vm: &mut VM) -> Value {
+        self.map_or(Value::Nil, |v| v.into_value(vm))
+    }
+}
+
+// Converted items are rooted until the list holding them is allocated.
+impl<T: IntoValue> IntoValue for Vec<T> {
+    fn into_value(self, vm: &mut VM) -> Value {
+        let base = vm.roots();
+        for item in self {
+            let v = item.into_value(vm);
+            vm.root(v);
+        }
+        Value::List(vm.alloc_from_roots(base, Obj::List))
+    }
+}
+
+impl<T: IntoValue> IntoValue for HashMap<String, T> {
+    fn into_value(self, vm: &mut VM) -> Value {
+        let base = vm.roots();
+        for (k, v) in self {
+            let k = vm.alloc_str(&k);
+            vm.root(k);
+            let v = v.into_value(vm);
+            vm.root(v);
+        }
+        Value::Map(vm.alloc_from_roots(base, |entries| Obj::Map(
+            entries.chunks(2).map(|entry| (entry[0].as_obj().expect("Expected a string key."), entry[1])).collect()
+        )))
+    }
+}
+
+// A Rust function that can be registered as a native, Args is the tuple of its argument types.
+// Arguments must convert without borrowing from the heap, so take String rather than &str.
+pub trait IntoNative<Args> {
+    fn arity(&self) -> usize;
+    fn into_native(self, name: &str) -> NativeFn;
+}
+
+// Points a failed conversion at the argument, counting from 1.
+fn argument_error(function: &str, index: usize, kind: RuntimeErrorKind) -> Error {
+    let kind = match kind {
+        RuntimeErrorKind::TypeMismatch { expected, got } =>
+            RuntimeErrorKind::InvalidArgument { function: function.to_string(), index: index + 1, expected, got },
+        kind => kind,
+    };
+    Error::Runtime { kind, span: Span::default() }
+}
+
+macro_rules! impl_into_native {
+    ($($arg:ident),*) => {
+        impl<F, R, $($arg,)*> IntoNative<($($arg,)*)> for F
+        where
+            F: Fn($($arg),*) -> R + 'static,
+            R: IntoValue,
+            $($arg: for<'a> FromValue<'a>,)*
+        {
+            fn arity(&self) -> usize {
+                <[&str]>::len(&[$(stringify!($arg)),*])
+            }
+
+            #[allow(non_snake_case, unused_variables, unused_mut)]
+            fn into_native(self, name: &str) -> NativeFn {
+                let name = name.to_string();
+                Rc::new(move |vm: &mut VM, args: &[Value]| {
+                    let mut args = args.iter().copied().enumerate();
+                    $(
+                        let (i, v) = args.next().expect("Expected an argument for each parameter.");
+                        let $arg = $arg::from_value(v, vm.heap()).map_err(|kind| argument_error(&name, i, kind))?;
+                    )*
+                    Ok(self($($arg),*).into_value(vm))
+                })
+            }
+        }
+    };
+}
+
+impl_into_native!();
+impl_into_native!(A);
+impl_into_native!(A, B);
+impl_into_native!(A, B, C);
+impl_into_native!(A, B, C, D);
+impl_into_native!(A, B, C, D, E);
+impl_into_native!(A, B, C, D, E, G);
@@ -0,0 +1,136 @@
+use std::fmt::Write;
+
+use crate::error::{CompileErrorKind, Error, LexErrorKind, RuntimeErrorKind, Span};
+
+const RED:   &str = "\x1b[1;31m";
+const BLUE:  &str = "\x1b[1;34m";
+const BOLD:  &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+// An error prepared for display against its source, in the style of rustc:
+//
+// error[runtime]: type mismatch or invalid '+' operation.
+//  --> script.rlox:2:11
+//   |
+// 2 | let y = x + true;
+//   |           ^
+//   = note: left operand is Number, right operand is Bool
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub stage:   &'static str,
+    pub message: String,
+    pub span:    Span,
+    pub notes:   Vec<String>,
+    pub help:    Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(stage: &'static str, message: String, span: Span) -> Self {
+        Self {
+            stage,
+            message,
+            span,
+            notes: Vec::new(),
+            help:  Vec::new(),
+        }
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    pub fn with_help(mut self, help: impl Into<String>) -> Self {
+        self.help.push(help.into());
+        self
+    }
+
+    // Renders the diagnostic for source read from file, with ANSI colours if colour is set.
+    pub fn render(&self, file: &str, source: &str, colour: bool) -> String {
+        let paint = |style: &str, text: &str| if colour { format!("{}{}{}", style, text, RESET) } else { text.to_string() };
+
+        // The line containing the start of the span, spans running past it are underlined to its end.
+        let start = self.span.start.min(source.len());
+        let line_start = source[..start].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[start..].find('\n').map_or(source.len(), |i| start + i);
+        let line = &source[line_start..line_end];
+        let underline = source[start..self.span.end.clamp(start, line_end)].chars().count().max(1);
+
+        let number = self.span.line.to_string();
+        let gutter = " ".repeat(number.len());
+        let bar = paint(BLUE, "|");
+
+        let mut out = String::new();
+        let _ = writeln!(out, "{}{}", paint(RED, &format!("error[{}]", self.stage)), paint(BOLD, &format!(": {}", self.message)));
+        let _ = writeln!(out, "{}{} {}:{}:{}", gutter, paint(BLUE, "-->"), file, self.span.line, self.span.column);
+        let _ = writeln!(out, "{} {}", gutter, bar);
+        let _ = writeln!(out, "{} {} {}", paint(BLUE, &number), bar, line);
+        let _ = writeln!(out, "{} {} {}{}", gutter, bar,
+            " ".repeat(source[line_start..start].chars().count()),
+            paint(RED, &"^".repeat(underline)));
+        for note in &self.notes {
+            let _ = writeln!(out, "{} {} {}", gutter, paint(BLUE, "="), paint(BOLD, &format!("note: {}", note)));
+        }
+        for help in &self.help {
+            let _ = writeln!(out, "{} {} {}", gutter, paint(BLUE, "="), paint(BOLD, &format!("help: {}", help)));
+        }
+        out
+    }
+}
+
+impl From<&Error> for Diagnostic {
+    fn from(error: &Error) -> Self {
+        match error {
+            Error::Lex { kind, span } => {
+                let diagnostic = Self::new("lex", kind.to_string(), *span);
+                match kind {
+                    LexErrorKind::InvalidEscape(_) => diagnostic
+                        .with_note("valid escapes are \\n, \\t, \\r, \\0, \\\\, \\\", \\', \\$ and \\u{...}")
+                        .with_help("use a raw string like r\"...\" to write backslashes as they are"),
+                    LexErrorKind::UnterminatedComment =>
+                        diagnostic.with_note("block comments nest, so each '#[' needs its own ']#'"),
+                    LexErrorKind::InvalidUnicodeEscape(_) =>
+                        diagnostic.with_help("write 1 to 6 hex digits in braces, like \\u{1F600}"),
+                    _ => diagnostic,
+                }
+            }
+            Error::Compile { kind, span } => {
+                let diagnostic = Self::new("compile", kind.to_string(), *span);
+                match kind {
+                    CompileErrorKind::InvalidDigit { radix, .. } => diagnostic.with_note(match radix {
+                        2  => "binary literals use the digits 0 and 1",
+                        8  => "octal literals use the digits 0 to 7",
+                        16 => "hexadecimal literals use the digits 0 to 9 and a to f",
+                        _  => "decimal literals use the digits 0 to 9, one '.' and an exponent like e-9",
+                    }),
+                    CompileErrorKind::ReadInOwnInitializer =>
+                        diagnostic.with_help("give the new variable a different name to use the outer one"),
+                    CompileErrorKind::AlreadyDeclared(_) =>
+                        diagnostic.with_help("assign to the existing variable instead of redeclaring it"),
+                    CompileErrorKind::ReturnFromInitializer =>
+                        diagnostic.with_note("initializers always return 'self'"),
+                    _ => diagnostic,
+                }
+            }
+            Error::Runtime { kind, span } => {
+                let diagnostic = Self::new("runtime", kind.to_string(), *span);
+                match kind {
+                    RuntimeErrorKind::InvalidOperands { op, left, right } => {
+                        let diagnostic = diagnostic.with_note(format!("left operand is {}, right operand is {}", left, right));
+                        if *op == "+" { diagnostic.with_help("'+' adds two numbers or joins two strings") }
+                        else { diagnostic }
+                    }
+                    RuntimeErrorKind::NotComparable { left, right, .. } =>
+                        diagnostic.with_note(format!("left operand is {}, right operand is {}", left, right)),
+                    RuntimeErrorKind::InvalidOperand { operand, .. } =>
+                        diagnostic.with_note(format!("operand is {}", operand)),
+                    RuntimeErrorKind::UndefinedVariable(_) =>
+                        diagnostic.with_help("declare it with 'let' before using it"),
+                    RuntimeErrorKind::UndefinedField(_) =>
+                        diagnostic.with_note("only fields declared in the struct can be assigned"),
+                    _ => diagnostic,
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,156 @@
+use std::fmt;
+
+// Where an error happened in the source.
+// Lines and columns count from 1, start..end is a byte range into the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub line:   usize,
+    pub column: usize,
+    pub start:  usize,
+    pub end:    usize,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LexErrorKind {
+    UnterminatedString,
+    UnterminatedComment,
+    UnknownCharacter(char),
+    InvalidEscape(String),
+    InvalidUnicodeEscape(String),
+}
+
+impl fmt::Display for LexErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnterminatedString  => write!(f, "unterminated string."),
+            Self::UnterminatedComment => write!(f, "unterminated block comment."),
+            Self::UnknownCharacter(c) => write!(f, "unknown character '{}'.", c),
+            Self::InvalidEscape(e)    => write!(f, "invalid escape sequence '{}'.", e),
+            Self::InvalidUnicodeEscape(e) => write!(f, "invalid unicode escape '{}'.", e),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum CompileErrorKind {
+    Expected(&'static str), // What the parser was looking for, e.g. "';' after expression"
+    ExpectedExpression,
+    InvalidAssignmentTarget,
+    InvalidNumber(String),
+    InvalidDigit { digit: char, radix: u32 },
+    MissingDigits(String),
+    MissingExponent(String),
+    ReadInOwnInitializer,
+    AlreadyDeclared(String),
+    InheritFromSelf,
+    SelfOutsideStruct,
+    SuperOutsideStruct,
+    SuperWithoutParent,
+    ReturnFromTopLevel,
+    ReturnFromInitializer,
+    BreakOutsideLoop,
+    ContinueOutsideLoop,
+}
+
+impl fmt::Display for CompileErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Expected(what)          => write!(f, "expected {}.", what),
+            Self::ExpectedExpression      => write!(f, "expected expression."),
+            Self::InvalidAssignmentTarget => write!(f, "invalid assignment target."),
+            Self::InvalidNumber(n)        => write!(f, "invalid number literal '{}'.", n),
+            Self::InvalidDigit { digit, radix } => write!(f, "invalid digit '{}' in base {} number literal.", digit, radix),
+            Self::MissingDigits(n)        => write!(f, "number literal '{}' has no digits.", n),
+            Self::MissingExponent(n)      => write!(f, "missing exponent digits in number literal '{}'.", n),
+            Self::ReadInOwnInitializer    => write!(f, "can't read local variable in its own initializer."),
+            Self::AlreadyDeclared(name)   => write!(f, "a variable named '{}' already exists in this scope.", name),
+            Self::InheritFromSelf         => write!(f, "a struct can't inherit from itself."),
+            Self::SelfOutsideStruct       => write!(f, "can't use 'self' outside of a struct."),
+            Self::SuperOutsideStruct      => write!(f, "can't use 'super' outside of a struct."),
+            Self::SuperWithoutParent      => write!(f, "can't use 'super' in a struct with no parent."),
+            Self::ReturnFromTopLevel      => write!(f, "can't return from top-level code."),
+            Self::ReturnFromInitializer   => write!(f, "can't return a value from an initializer."),
+            Self::BreakOutsideLoop        => write!(f, "can't use 'break' outside of a loop."),
+            Self::ContinueOutsideLoop     => write!(f, "can't use 'continue' outside of a loop."),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RuntimeErrorKind {
+    // The operator, e.g. "+", and the type names of its operands
+    InvalidOperands { op: &'static str, left: &'static str, right: &'static str },
+    InvalidOperand  { op: &'static str, operand: &'static str },
+    NotComparable   { op: &'static str, left: &'static str, right: &'static str },
+    UndefinedVariable(String),
+    UndefinedProperty(String),
+    UndefinedField(String),
+    NotAnInstance(&'static str), // What was accessed: "properties", "methods" or "fields"
+    NotCallable,
+    ArityMismatch { expected: usize, got: usize },
+    ParentNotStruct,
+    StackOverflow,
+    NoChunkLoaded,
+    Native(String), // Raised by a native function, with its message
+    // A value converted to a Rust type, with the expected and actual type names
+    TypeMismatch { expected: &'static str, got: &'static str },
+    InvalidArgument { function: String, index: usize, expected: &'static str, got: &'static str }, // index counts from 1
+    Output(String), // Writing to the VM's output failed, with the io error
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidOperands { op, .. } => write!(f, "type mismatch or invalid '{}' operation.", op),
+            Self::InvalidOperand { op, .. }  => write!(f, "invalid operand for unary '{}'.", op),
+            Self::NotComparable { op, .. }   => write!(f, "only numerical types are comparable, near '{}'.", op),
+            Self::UndefinedVariable(name) => write!(f, "undefined variable '{}'.", name),
+            Self::UndefinedProperty(name) => write!(f, "undefined property '{}'.", name),
+            Self::UndefinedField(name)    => write!(f, "undefined field '{}'.", name),
+            Self::NotAnInstance(what)     => write!(f, "only instances have {}.", what),
+            Self::NotCallable             => write!(f, "can only call functions and structs."),
+            Self::ArityMismatch { expected, got } => write!(f, "expected {} arguments but got {}.", expected, got),
+            Self::ParentNotStruct         => write!(f, "parent must be a struct."),
+            Self::StackOverflow           => write!(f, "stack overflow."),
+            Self::NoChunkLoaded           => write!(f, "no chunk has been loaded."),
+            Self::Native(message)         => write!(f, "{}", message),
+            Self::TypeMismatch { expected, got } => write!(f, "expected {} but got {}.", expected, got),
+            Self::InvalidArgument { function, index, expected, got } =>
+                write!(f, "argument {} of '{}' expected {} but got {}.", index, function, expected, got),
+            Self::Output(e)               => write!(f, "failed to write output: {}.", e),
+        }
+    }
+}
+
+// An error from any stage of running a script.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Error {
+    Lex     { kind: LexErrorKind,     span: Span },
+    Compile { kind: CompileErrorKind, span: Span },
+    Runtime { kind: RuntimeErrorKind, span: Span },
+}
+
+impl Error {
+    // An error for a native function to return, the VM points it at the script's call.
+    pub fn native(message: impl Into<String>) -> Self {
+        Self::Runtime { kind: RuntimeErrorKind::Native(message.into()), span: Span::default() }
+    }
+
+    pub fn span(&self) -> Span {
+        match self {
+            Self::Lex { span, .. } | Self::Compile { span, .. } | Self::Runtime { span, .. } => *span,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Lex     { kind, span } => write!(f, "Lex error, at line {}: {}", span.line, kind),
+            Self::Compile { kind, span } => write!(f, "Compile error, at line {}: {}", span.line, kind),
+            Self::Runtime { kind, span } => write!(f, "Runtime error, at line {}: {}", span.line, kind),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
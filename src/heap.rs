@@ -0,0 +1,321 @@
+use std::{collections::HashMap, mem::{size_of, size_of_val}};
+
+use crate::{
+    util::KeyedArray,
+    value::{BoundMethod, Closure, Function, Instance, Native, Struct, Upvalue, UserDataCell, Value},
+};
+
+// Handle to an object on the heap.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct ObjRef(usize);
+
+#[derive(Debug, Clone)]
+pub enum Obj {
+    Str(String),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Struct(Struct),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+    List(Vec<Value>),
+    Map(HashMap<ObjRef, Value>), // Keyed by interned string
+    UserData(UserDataCell),
+}
+
+#[derive(Clone)]
+struct Entry {
+    obj: Obj,
+    size: usize, // Bytes accounted to the object when it was allocated
+    marked: bool,
+}
+
+// Garbage collected object storage, using a tracing mark-and-sweep collector.
+// The heap doesn't know its roots, so the owner marks them with mark_value/mark_obj before calling collect.
+pub struct Heap {
+    objects: KeyedArray<Entry>,
+    // Every live string, so each distinct string is only allocated once.
+    // Entries don't keep their string alive, they are dropped when it is swept.
+    strings: HashMap<String, ObjRef>,
+    gray: Vec<ObjRef>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    // Collect before every allocation, to flush out objects that aren't rooted properly.
+    pub stress: bool,
+}
+
+impl Heap {
+    const SIZE: usize = 256;
+    const INITIAL_GC: usize = 1024 * 1024;
+    const GROWTH_FACTOR: usize = 2;
+
+    pub fn new() -> Self {
+        Self {
+            objects: KeyedArray::new(Self::SIZE),
+            strings: HashMap::new(),
+            gray: Vec::new(),
+            bytes_allocated: 0,
+            next_gc: Self::INITIAL_GC,
+            stress: false,
+        }
+    }
+
+    fn size_of(obj: &Obj) -> usize {
+        size_of::<Entry>() + match obj {
+            Obj::Str(s) => s.capacity(),
+            Obj::Function(f) => f.chunk.code.capacity() * size_of::<crate::vm::Op>()
+                              + f.chunk.span_runs() * size_of::<(usize, crate::error::Span)>()
+                              + f.chunk.constants().count() * size_of::<Option<Value>>(),
+            Obj::Closure(c) => c.upvalues.capacity() * size_of::<ObjRef>(),
+            Obj::Struct(s) => s.fields.capacity() * size_of::<(ObjRef, Option<ObjRef>)>()
+                            + s.methods.capacity() * size_of::<(ObjRef, ObjRef)>(),
+            Obj::Instance(i) => i.fields.capacity() * size_of::<(ObjRef, Value)>(),
+            Obj::Native(n) => n.name.capacity(),
+            Obj::List(l) => l.capacity() * size_of::<Value>(),
+            Obj::Map(m) => m.capacity() * size_of::<(ObjRef, Value)>(),
+            Obj::UserData(u) => size_of_val(&*u.0.borrow()),
+            Obj::Upvalue(_) | Obj::BoundMethod(_) => 0,
+        }
+    }
+
+    // Allocates without collecting, callers decide when it is safe to collect.
+    pub fn alloc(&mut self, obj: Obj) -> ObjRef {
+        let size = Self::size_of(&obj);
+        self.bytes_allocated += size;
+        ObjRef(self.objects.push(Entry { obj, size, marked: false }))
+    }
+
+    // Strings must be allocated through here rather than alloc, so they stay unique.
+    pub fn alloc_str(&mut self, s: &str) -> ObjRef {
+        match self.find_string(s) {
+            Some(r) => r,
+            None => self.intern(s.to_string()),
+        }
+    }
+
+    pub fn intern(&mut self, s: String) -> ObjRef {
+        if let Some(r) = self.find_string(&s) {
+            return r;
+        }
+        let r = self.alloc(Obj::Str(s.clone()));
+        self.strings.insert(s, r);
+        r
+    }
+
+    pub fn find_string(&self, s: &str) -> Option<ObjRef> {
+        self.strings.get(s).copied()
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    // Number of live objects.
+    pub fn len(&self) -> usize {
+        self.objects.iter().count()
+    }
+
+    pub fn get(&self, r: ObjRef) -> &Obj {
+        &self.objects[r.0].obj
+    }
+
+    pub fn get_mut(&mut self, r: ObjRef) -> &mut Obj {
+        &mut self.objects[r.0].obj
+    }
+
+    pub fn string(&self, r: ObjRef) -> &str {
+        match self.get(r) {
+            Obj::Str(s) => s,
+            obj => panic!("Expected a string object, found {:?}", obj),
+        }
+    }
+
+    pub fn function(&self, r: ObjRef) -> &Function {
+        match self.get(r) {
+            Obj::Function(f) => f,
+            obj => panic!("Expected a function object, found {:?}", obj),
+        }
+    }
+
+    pub fn closure(&self, r: ObjRef) -> &Closure {
+        match self.get(r) {
+            Obj::Closure(c) => c,
+            obj => panic!("Expected a closure object, found {:?}", obj),
+        }
+    }
+
+    pub fn upvalue(&self, r: ObjRef) -> &Upvalue {
+        match self.get(r) {
+            Obj::Upvalue(u) => u,
+            obj => panic!("Expected an upvalue object, found {:?}", obj),
+        }
+    }
+
+    pub fn upvalue_mut(&mut self, r: ObjRef) -> &mut Upvalue {
+        match self.get_mut(r) {
+            Obj::Upvalue(u) => u,
+            obj => panic!("Expected an upvalue object, found {:?}", obj),
+        }
+    }
+
+    pub fn structure(&self, r: ObjRef) -> &Struct {
+        match self.get(r) {
+            Obj::Struct(s) => s,
+            obj => panic!("Expected a struct object, found {:?}", obj),
+        }
+    }
+
+    pub fn structure_mut(&mut self, r: ObjRef) -> &mut Struct {
+        match self.get_mut(r) {
+            Obj::Struct(s) => s,
+            obj => panic!("Expected a struct object, found {:?}", obj),
+        }
+    }
+
+    pub fn instance(&self, r: ObjRef) -> &Instance {
+        match self.get(r) {
+            Obj::Instance(i) => i,
+            obj => panic!("Expected an instance object, found {:?}", obj),
+        }
+    }
+
+    pub fn instance_mut(&mut self, r: ObjRef) -> &mut Instance {
+        match self.get_mut(r) {
+            Obj::Instance(i) => i,
+            obj => panic!("Expected an instance object, found {:?}", obj),
+        }
+    }
+
+    pub fn bound_method(&self, r: ObjRef) -> &BoundMethod {
+        match self.get(r) {
+            Obj::BoundMethod(b) => b,
+            obj => panic!("Expected a bound method object, found {:?}", obj),
+        }
+    }
+
+    pub fn native(&self, r: ObjRef) -> &Native {
+        match self.get(r) {
+            Obj::Native(n) => n,
+            obj => panic!("Expected a native function object, found {:?}", obj),
+        }
+    }
+
+    pub fn list(&self, r: ObjRef) -> &Vec<Value> {
+        match self.get(r) {
+            Obj::List(l) => l,
+            obj => panic!("Expected a list object, found {:?}", obj),
+        }
+    }
+
+    pub fn map(&self, r: ObjRef) -> &HashMap<ObjRef, Value> {
+        match self.get(r) {
+            Obj::Map(m) => m,
+            obj => panic!("Expected a map object, found {:?}", obj),
+        }
+    }
+
+    pub fn userdata(&self, r: ObjRef) -> &UserDataCell {
+        match self.get(r) {
+            Obj::UserData(u) => u,
+            obj => panic!("Expected a userdata object, found {:?}", obj),
+        }
+    }
+
+    pub fn mark_value(&mut self, value: Value) {
+        if let Some(r) = value.as_obj() {
+            self.mark_obj(r);
+        }
+    }
+
+    pub fn mark_obj(&mut self, r: ObjRef) {
+        let entry = &mut self.objects[r.0];
+        if entry.marked { return }
+        entry.marked = true;
+        self.gray.push(r);
+    }
+
+    // Marks every object directly referenced by r.
+    fn blacken(&mut self, r: ObjRef) {
+        let mut children = Vec::new();
+        match self.get(r) {
+            Obj::Str(_) | Obj::Native(_) => (),
+            Obj::Function(f) => {
+                children.extend(f.chunk.constants().filter_map(|v| v.as_obj()));
+            }
+            Obj::Closure(c) => {
+                children.push(c.function);
+                children.extend(&c.upvalues);
+            }
+            Obj::Upvalue(u) => {
+                if let Upvalue::Closed(v) = u {
+                    children.extend(v.as_obj());
+                }
+            }
+            Obj::Struct(s) => {
+                children.push(s.name);
+                children.extend(s.fields.iter().flat_map(|&(name, default)| std::iter::once(name).chain(default)));
+                children.extend(s.methods.keys());
+                children.extend(s.methods.values());
+            }
+            Obj::Instance(i) => {
+                children.push(i.structure);
+                children.extend(i.fields.keys());
+                children.extend(i.fields.values().filter_map(Value::as_obj));
+            }
+            Obj::BoundMethod(b) => {
+                children.extend(b.receiver.as_obj());
+                children.push(b.method);
+            }
+            Obj::List(l) => {
+                children.extend(l.iter().filter_map(Value::as_obj));
+            }
+            Obj::Map(m) => {
+                children.extend(m.keys());
+                children.extend(m.values().filter_map(Value::as_obj));
+            }
+            Obj::UserData(u) => {
+                let mut values = Vec::new();
+                u.0.borrow().trace(&mut values);
+                children.extend(values.iter().filter_map(Value::as_obj));
+            }
+        }
+
+        for child in children {
+            self.mark_obj(child);
+        }
+    }
+
+    // Traces everything reachable from the marked roots, then frees every unmarked object.
+    pub fn collect(&mut self) {
+        while let Some(r) = self.gray.pop() {
+            self.blacken(r);
+        }
+
+        let objects = &self.objects;
+        self.strings.retain(|_, r| objects[r.0].marked);
+
+        let unreachable: Vec<usize> = self.objects.iter()
+            .filter(|(_, entry)| !entry.marked)
+            .map(|(id, _)| id)
+            .collect();
+        for id in unreachable {
+            if let Obj::UserData(u) = &self.objects[id].obj {
+                u.0.borrow_mut().finalize();
+            }
+            self.bytes_allocated -= self.objects[id].size;
+            self.objects.remove(id);
+        }
+
+        let live: Vec<usize> = self.objects.iter().map(|(id, _)| id).collect();
+        for id in live {
+            self.objects[id].marked = false;
+        }
+
+        self.next_gc = (self.bytes_allocated * Self::GROWTH_FACTOR).max(Self::INITIAL_GC);
+    }
+}
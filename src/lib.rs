@@ -3,27 +3,35 @@ pub mod compiler;
 pub mod vm;
 pub mod value;
 pub mod util;
+pub mod heap;
+pub mod error;
+pub mod diagnostic;
+pub mod convert;
+
+pub use error::Error;
 
 
 #[cfg(test)]
 mod tests {
-    use crate::{compiler, scanner::{Scanner, TokenType}, value::Value, vm::{Chunk, Op, VM}};
+    use crate::{
+        compiler, convert::{FromValue, IntoValue}, diagnostic::Diagnostic, error::{CompileErrorKind, Error, LexErrorKind, RuntimeErrorKind, Span}, heap::Heap, scanner::{Scanner, TokenType}, value::Value, vm::{Chunk, Op, VM},
+    };
     
     #[test]
     fn vm() {
         let mut chunk = Chunk::new();
         chunk
-            .push_constant(Value::Number(5.0), 0)
-            .push_constant(Value::Number(5.0), 0)
-            .push_operation(Op::Add, 0)
-            .push_constant(Value::Number(4.0), 0)
-            .push_operation(Op::Sub, 0)
-            .push_constant(Value::Number(3.0), 0)
-            .push_operation(Op::Mul, 0)
-            .push_constant(Value::Number(2.0), 0)
-            .push_operation(Op::Div, 0)
-            .push_operation(Op::Negate, 0)
-            .push_operation(Op::Return, 0);
+            .push_constant(Value::Number(5.0), Span::default())
+            .push_constant(Value::Number(5.0), Span::default())
+            .push_operation(Op::Add, Span::default())
+            .push_constant(Value::Number(4.0), Span::default())
+            .push_operation(Op::Sub, Span::default())
+            .push_constant(Value::Number(3.0), Span::default())
+            .push_operation(Op::Mul, Span::default())
+            .push_constant(Value::Number(2.0), Span::default())
+            .push_operation(Op::Div, Span::default())
+            .push_operation(Op::Negate, Span::default())
+            .push_operation(Op::Return, Span::default());
         let mut vm = VM::new();
         vm.load_chunk(chunk);
         assert_eq!(vm.execute_loaded_chunk(), Ok(Value::Number(-9.0)));
@@ -100,6 +108,16 @@ mod tests {
         assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::Semicolon);
         assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::RBrace);
         assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::Eof);
+        assert_eq!(scanner.line, 7);
+
+        // Multi-byte chars and comments on their own lines
+        let src = "# héllo\n\"ünï\" # wörld\n  # again\nlet";
+        let mut scanner = Scanner::new(src);
+        let token = scanner.scan_token().unwrap();
+        assert_eq!(token.t_type, TokenType::Str);
+        assert_eq!(token.slice, "\"ünï\"");
+        assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::Let);
+        assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::Eof);
         assert_eq!(scanner.line, 4);
     }
 
@@ -107,7 +125,7 @@ mod tests {
     fn compiler() {
         // Test math expression
         let src = "-(5 + 4) * 2 / 2;";
-        let chunk = compiler::compile(src).unwrap();
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
         
         let expected = [
             Op::LoadConst(0),
@@ -118,80 +136,1045 @@ mod tests {
             Op::Mul,
             Op::LoadConst(3),
             Op::Div,
-            Op::Pop,
+            Op::Return,
         ];
 
-        for (i, (op, _)) in chunk.code.iter().enumerate() {
+        for (i, op) in chunk.code.iter().enumerate() {
             assert_eq!(*op, expected[i]);  
         }
 
         // Test boolean expressions
         let src = "true and false or false and false;";
-        let chunk = compiler::compile(src).unwrap();
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
 
         let expected = [
             Op::True,
+            Op::JumpIfFalse(2),
+            Op::Pop,
             Op::False,
-            Op::And,
+            Op::JumpIfFalse(1),
+            Op::Jump(5),
+            Op::Pop,
             Op::False,
+            Op::JumpIfFalse(2),
+            Op::Pop,
             Op::False,
-            Op::And,
-            Op::Or,
-            Op::Pop,    
+            Op::Return,
         ];
 
-        for (i, (op, _)) in chunk.code.iter().enumerate() {
+        for (i, op) in chunk.code.iter().enumerate() {
             assert_eq!(*op, expected[i]);  
         }
 
         // Test strings
         let src = "\"Hello, \" + \"World\";";
-        let chunk = compiler::compile(src).unwrap();
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
 
         let expected = [
             Op::LoadConst(0),
             Op::LoadConst(1),
             Op::Add,
-            Op::Pop,    
+            Op::Return,
         ];
 
-        for (i, (op, _)) in chunk.code.iter().enumerate() {
+        for (i, op) in chunk.code.iter().enumerate() {
             assert_eq!(*op, expected[i]);  
         }
 
         // Test comparison
         let src = "5 == 5 and 5 != 4 and 5 > 4 and 4 < 5 and 5 >= 4 and 4 <= 5;";
-        let chunk = compiler::compile(src).unwrap();
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
 
         let expected = [
             Op::LoadConst(0),
             Op::LoadConst(1),
             Op::Equal,
+            Op::JumpIfFalse(4),
+            Op::Pop,
             Op::LoadConst(2),
             Op::LoadConst(3),
             Op::NotEqual,
-            Op::And,
+            Op::JumpIfFalse(4),
+            Op::Pop,
             Op::LoadConst(4),
             Op::LoadConst(5),
             Op::GreaterThan,
-            Op::And,
+            Op::JumpIfFalse(4),
+            Op::Pop,
             Op::LoadConst(6),
             Op::LoadConst(7),
             Op::LessThan,
-            Op::And,
+            Op::JumpIfFalse(4),
+            Op::Pop,
             Op::LoadConst(8),
             Op::LoadConst(9),
             Op::GreaterEq,
-            Op::And,
+            Op::JumpIfFalse(4),
+            Op::Pop,
             Op::LoadConst(10),
             Op::LoadConst(11),
             Op::LessEq,
-            Op::And,
-            Op::Pop,    
+            Op::Return,
         ];
 
-        for (i, (op, _)) in chunk.code.iter().enumerate() {
+        for (i, op) in chunk.code.iter().enumerate() {
             assert_eq!(*op, expected[i]);  
         }
     }
+
+    #[test]
+    fn locals() {
+        // Locals resolve to stack slots after the callee's slot 0, and are popped at scope exit
+        let src = "{ let a = 1; let b = a; b = 2; }";
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
+
+        let expected = [
+            Op::LoadConst(0),
+            Op::GetLocal(1),
+            Op::LoadConst(1),
+            Op::SetLocal(2),
+            Op::Pop,
+            Op::Pop,
+            Op::Pop,
+        ];
+
+        assert_eq!(chunk.code.len(), expected.len());
+        for (i, op) in chunk.code.iter().enumerate() {
+            assert_eq!(*op, expected[i]);
+        }
+
+        // Shadowing
+        let src = "let x = 0; { let a = 1; { let a = 2; x = a; } x = x + a; }";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("x"), Some(&Value::Number(3.0)));
+
+        // Errors
+        assert!(compiler::compile("{ let a = 1; { let a = a; } }", &mut Heap::new()).is_err());
+        assert!(compiler::compile("{ let a = 1; let a = 2; }", &mut Heap::new()).is_err());
+        assert!(compiler::compile("{ let a = 1;", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn if_else() {
+        let src = "if (true) print 1; else print 2;";
+        let chunk = compiler::compile(src, &mut Heap::new()).unwrap();
+
+        let expected = [
+            Op::True,
+            Op::JumpIfFalse(4),
+            Op::Pop,
+            Op::LoadConst(0),
+            Op::Print,
+            Op::Jump(3),
+            Op::Pop,
+            Op::LoadConst(1),
+            Op::Print,
+        ];
+
+        assert_eq!(chunk.code.len(), expected.len());
+        for (i, op) in chunk.code.iter().enumerate() {
+            assert_eq!(*op, expected[i]);
+        }
+
+        let src = "
+            let a = 0; let b = 0; let c = 0;
+            if (1 > 2) a = 1; else a = 2;
+            if (nil) { b = 1; }
+            if (0) { if (false) c = 1; else c = 3; }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("a"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.get_global("b"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.get_global("c"), Some(&Value::Number(3.0)));
+
+        assert!(compiler::compile("if true print 1;", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn loops() {
+        let src = "
+            let sum = 0;
+            let i = 0;
+            while (i < 10) { i = i + 1; sum = sum + i; }
+
+            let counted = 0;
+            for (let j = 0; j < 100; j = j + 1) {
+                let skip = j == 3 or j == 5;
+                if (j > 9) break;
+                if (skip) continue;
+                counted = counted + 1;
+            }
+
+            let outer = 0;
+            for (;;) {
+                let a = 1;
+                while (true) { let b = 2; break; }
+                outer = outer + a;
+                if (outer >= 3) { let c = 3; break; }
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("sum"), Some(&Value::Number(55.0)));
+        assert_eq!(vm.get_global("counted"), Some(&Value::Number(8.0)));
+        assert_eq!(vm.get_global("outer"), Some(&Value::Number(3.0)));
+
+        // Loop-local variables are popped on break and continue
+        let src = "let x = 0; { let a = 7; while (true) { let b = 1; break; } x = a; }";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("x"), Some(&Value::Number(7.0)));
+
+        assert!(compiler::compile("break;", &mut Heap::new()).is_err());
+        assert!(compiler::compile("{ continue; }", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn logical() {
+        let src = "
+            let a = nil or \"default\";
+            let b = 0 and 2;
+            let c = false and undefined;
+            let d = 1 or undefined;
+            let e = nil and 1;
+            let f = !nil and !!\"\";
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("a").unwrap().display(vm.heap()), "default");
+        assert_eq!(vm.get_global("b"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.get_global("c"), Some(&Value::Bool(false)));
+        assert_eq!(vm.get_global("d"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.get_global("e"), Some(&Value::Nil));
+        assert_eq!(vm.get_global("f"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn functions() {
+        let src = "
+            fn add(a, b) { return a + b; }
+            fn fib(n) {
+                if (n < 2) return n;
+                return fib(n - 1) + fib(n - 2);
+            }
+            fn nothing() { let x = 1; }
+            fn early(n) {
+                for (let i = 0; i < 10; i = i + 1) {
+                    if (i == n) return i * 10;
+                }
+                return -1;
+            }
+
+            let sum = add(1, 2);
+            let f = fib(10);
+            let n = nothing();
+            let e = early(3);
+            let g = add;
+            let h = g(add(1, 1), 3);
+            {
+                fn local(x) { if (x > 0) return x; return \"done\"; }
+                h = h + e;
+                n = local(0);
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("sum"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("f"), Some(&Value::Number(55.0)));
+        assert_eq!(vm.get_global("e"), Some(&Value::Number(30.0)));
+        assert_eq!(vm.get_global("h"), Some(&Value::Number(35.0)));
+        assert_eq!(vm.get_global("n").unwrap().display(vm.heap()), "done");
+
+        // Runtime errors
+        let mut vm = VM::new();
+        assert!(vm.interpret("fn f(a) {} f(1, 2);").is_err());
+        assert!(vm.interpret("let x = 1; x();").is_err());
+        assert!(vm.interpret("fn f() { f(); } f();").is_err());
+        // The VM recovers after an error
+        assert!(vm.interpret("let y = 2;").is_ok());
+
+        assert!(compiler::compile("return 1;", &mut Heap::new()).is_err());
+        assert!(compiler::compile("fn f(a, ) {}", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn closures() {
+        let src = "
+            fn makeCounter() {
+                let count = 0;
+                fn increment() { count = count + 1; return count; }
+                return increment;
+            }
+            let counter = makeCounter();
+            counter();
+            counter();
+            let a = counter();
+            let b = makeCounter()();
+
+            let get; let set;
+            {
+                let shared = 1;
+                fn g() { return shared; }
+                fn s(v) { shared = v; }
+                get = g; set = s;
+            }
+            set(10);
+            let c = get();
+
+            fn outer() {
+                let x = \"outer\";
+                fn middle() {
+                    fn inner() { return x; }
+                    return inner;
+                }
+                return middle();
+            }
+            let d = outer()();
+
+            let first; let second;
+            for (let i = 0; i < 2; i = i + 1) {
+                let j = i;
+                fn capture() { return j; }
+                if (i == 0) first = capture; else second = capture;
+            }
+            let e = first() + second() * 10;
+
+            let f;
+            {
+                fn countdown(n) { if (n > 0) return countdown(n - 1); return \"done\"; }
+                f = countdown(3);
+            }
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("a"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("b"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.get_global("c"), Some(&Value::Number(10.0)));
+        assert_eq!(vm.get_global("d").unwrap().display(vm.heap()), "outer");
+        assert_eq!(vm.get_global("e"), Some(&Value::Number(10.0)));
+        assert_eq!(vm.get_global("f").unwrap().display(vm.heap()), "done");
+    }
+
+    #[test]
+    fn structs() {
+        let src = "
+            struct Point {
+                x = 0;
+                y = 0;
+                label;
+
+                fn init(x, y) {
+                    self.x = x;
+                    self.y = y;
+                }
+
+                fn sum() { return self.x + self.y; }
+
+                fn scaled(n) {
+                    fn scale(v) { return v * n; }
+                    return Point(scale(self.x), scale(self.y));
+                }
+            }
+
+            struct Counter {
+                count = 0;
+                fn bump() { self.count = self.count + 1; return self; }
+            }
+
+            let p = Point(1, 2);
+            let sum = p.sum();
+            let q = p.scaled(10);
+            let qx = q.x;
+            let method = q.sum;
+            let bound = method();
+            let label = p.label;
+
+            let c = Counter();
+            c.bump().bump().bump();
+            let count = c.count;
+            let other = Counter().count;
+
+            let same = p == p;
+            let different = p == q;
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("sum"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("qx"), Some(&Value::Number(10.0)));
+        assert_eq!(vm.get_global("bound"), Some(&Value::Number(30.0)));
+        assert_eq!(vm.get_global("label"), Some(&Value::Nil));
+        assert_eq!(vm.get_global("count"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("other"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.get_global("same"), Some(&Value::Bool(true)));
+        assert_eq!(vm.get_global("different"), Some(&Value::Bool(false)));
+
+        // Runtime errors
+        let mut vm = VM::new();
+        assert!(vm.interpret("struct A { x = 1; } A().y;").is_err());
+        assert!(vm.interpret("struct A { x = 1; } A().y = 2;").is_err());
+        assert!(vm.interpret("struct A { x = 1; } A(1);").is_err());
+        assert!(vm.interpret("struct A { fn init(a) {} } A();").is_err());
+        assert!(vm.interpret("let n = 1; n.x;").is_err());
+
+        assert!(compiler::compile("self.x;", &mut Heap::new()).is_err());
+        assert!(compiler::compile("fn f() { return self; }", &mut Heap::new()).is_err());
+        assert!(compiler::compile("struct A { fn init() { return 1; } }", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn field_defaults() {
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+        vm.interpret("
+            let made = 0;
+            struct B { n = 1; fn get() { return self.n; } }
+            struct A { inner = B(); id = made = made + 1; tag; next = self.id + 1; }
+            let first = A();
+            let second = A();
+            first.inner.n = 5;
+            let changed = first.inner.n;
+            let unchanged = second.inner.n;
+            let ids = first.id + second.id * 10;
+            let next = second.next;
+            let tag = first.tag;
+        ").unwrap();
+        assert_eq!(vm.get_global("changed"), Some(&Value::Number(5.0)));
+        assert_eq!(vm.get_global("unchanged"), Some(&Value::Number(1.0)));
+        assert_eq!(vm.get_global("made"), Some(&Value::Number(2.0)));
+        assert_eq!(vm.get_global("ids"), Some(&Value::Number(21.0)));
+        assert_eq!(vm.get_global("next"), Some(&Value::Number(3.0)));
+        assert_eq!(vm.get_global("tag"), Some(&Value::Nil));
+
+        // Inherited defaults run too, a redeclared field replaces its parent's default, and super works in them
+        vm.interpret("
+            struct C < B { n = 2; sum = self.n + super.get(); }
+            let sum = C().sum;
+        ").unwrap();
+        assert_eq!(vm.get_global("sum"), Some(&Value::Number(4.0)));
+
+        // Defaults run in frames of their own, so endless nesting overflows the VM's frames and not the native stack
+        let mut vm = VM::new();
+        let Error::Runtime { kind, .. } = vm.interpret("struct E { e = E(); } E();").unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, RuntimeErrorKind::StackOverflow);
+
+        // Errors in a default point at it
+        let Error::Runtime { kind, span } = vm.interpret("struct D {\n  x = -nil;\n}\nD();").unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, RuntimeErrorKind::InvalidOperand { op: "-", operand: "Nil" });
+        assert_eq!(span.line, 2);
+    }
+
+    #[test]
+    fn inheritance() {
+        let src = "
+            struct Shape {
+                name = \"shape\";
+                sides = 0;
+                fn init(sides) { self.sides = sides; }
+                fn describe() { return self.name; }
+                fn area() { return 0; }
+            }
+
+            struct Square < Shape {
+                size = 1;
+                fn init(size) {
+                    super.init(4);
+                    self.size = size;
+                }
+                fn area() { return self.size * self.size; }
+                fn parentArea() { return super.area(); }
+            }
+
+            struct Cube < Square {
+                fn area() {
+                    let face = super.area;
+                    return face() * 6;
+                }
+            }
+
+            let s = Square(3);
+            let area = s.area();
+            let parent = s.parentArea();
+            let sides = s.sides;
+            let name = s.describe();
+            let cube = Cube(2).area();
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("area"), Some(&Value::Number(9.0)));
+        assert_eq!(vm.get_global("parent"), Some(&Value::Number(0.0)));
+        assert_eq!(vm.get_global("sides"), Some(&Value::Number(4.0)));
+        assert_eq!(vm.get_global("name").unwrap().display(vm.heap()), "shape");
+        assert_eq!(vm.get_global("cube"), Some(&Value::Number(24.0)));
+
+        let mut vm = VM::new();
+        assert!(vm.interpret("let x = 1; struct A < x {}").is_err());
+
+        assert!(compiler::compile("struct A < A {}", &mut Heap::new()).is_err());
+        assert!(compiler::compile("struct A { fn f() { return super.f(); } }", &mut Heap::new()).is_err());
+        assert!(compiler::compile("super.f();", &mut Heap::new()).is_err());
+    }
+
+    #[test]
+    fn gc() {
+        // Collecting before every allocation mustn't free anything still in use
+        let src = "
+            struct Node {
+                next = nil;
+                name = \"\";
+                fn init(name) { self.name = name; }
+                fn label() { return \"node \" + self.name; }
+            }
+
+            fn makeCounter() {
+                let count = 0;
+                fn inc() { count = count + 1; return count; }
+                return inc;
+            }
+
+            let counter = makeCounter();
+            let label = \"\";
+            for (let i = 0; i < 10; i = i + 1) {
+                let node = Node(\"a\" + \"b\");
+                label = node.label();
+                counter();
+            }
+            let count = counter();
+        ";
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("label").unwrap().display(vm.heap()), "node ab");
+        assert_eq!(vm.get_global("count"), Some(&Value::Number(11.0)));
+
+        // Unreachable cycles are freed
+        let mut vm = VM::new();
+        vm.interpret("struct Node { next = nil; }").unwrap();
+        vm.collect_garbage();
+        let live = vm.heap().len();
+        let bytes = vm.heap().bytes_allocated();
+
+        vm.interpret("
+            for (let i = 0; i < 100; i = i + 1) {
+                let a = Node();
+                let b = Node();
+                a.next = b;
+                b.next = a;
+            }
+        ").unwrap();
+        assert!(vm.heap().len() > live);
+        vm.collect_garbage();
+        assert_eq!(vm.heap().len(), live);
+        assert_eq!(vm.heap().bytes_allocated(), bytes);
+    }
+
+    #[test]
+    fn interning() {
+        // Identical strings share one object, however they were made
+        let src = "
+            let a = \"hello\";
+            let b = \"hel\" + \"lo\";
+            let same = a == b;
+            let different = a != \"world\";
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("a"), vm.get_global("b"));
+        assert_eq!(vm.get_global("same"), Some(&Value::Bool(true)));
+        assert_eq!(vm.get_global("different"), Some(&Value::Bool(true)));
+
+        let mut heap = Heap::new();
+        let a = heap.alloc_str("name");
+        assert_eq!(heap.alloc_str("name"), a);
+        assert_eq!(heap.intern("name".to_string()), a);
+        assert_ne!(heap.alloc_str("other"), a);
+
+        // Unreachable strings leave the intern table when collected
+        heap.collect();
+        assert_eq!(heap.find_string("name"), None);
+        assert_eq!(heap.len(), 0);
+    }
+
+    #[test]
+    fn errors() {
+        let mut heap = Heap::new();
+
+        let Error::Lex { kind, span } = compiler::compile("let s = \"abc;", &mut heap).unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, LexErrorKind::UnterminatedString);
+        assert_eq!(span.line, 1);
+
+        let Error::Compile { kind, .. } = compiler::compile("print 1", &mut heap).unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, CompileErrorKind::Expected("';' after expression"));
+
+        let Error::Compile { kind, .. } = compiler::compile("return 1;", &mut heap).unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, CompileErrorKind::ReturnFromTopLevel);
+
+        let mut vm = VM::new();
+        let Error::Runtime { kind, .. } = vm.interpret("let x = -true;").unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, RuntimeErrorKind::InvalidOperand { op: "-", operand: "Bool" });
+
+        let err = vm.interpret("let y = missing;").unwrap_err().remove(0);
+        assert_eq!(err, Error::Runtime {
+            kind: RuntimeErrorKind::UndefinedVariable("missing".to_string()),
+            span: err.span(),
+        });
+        assert_eq!(err.to_string(), "Runtime error, at line 1: undefined variable 'missing'.");
+
+        let Error::Runtime { kind, .. } = vm.interpret("fn f(a) {} f();").unwrap_err().remove(0) else { panic!() };
+        assert_eq!(kind, RuntimeErrorKind::ArityMismatch { expected: 1, got: 0 });
+
+        // Usable as a boxed std error
+        let boxed: Box<dyn std::error::Error> = Box::new(err);
+        assert!(boxed.to_string().contains("missing"));
+    }
+
+    #[test]
+    fn spans() {
+        // Columns count chars, start and end are byte offsets
+        let src = "let é = 1;\n  print é;";
+        let mut scanner = Scanner::new(src);
+        let expected = [
+            (TokenType::Let,        1, 1, 0, 3),
+            (TokenType::Identifier, 1, 5, 4, 6),
+            (TokenType::Equal,      1, 7, 7, 8),
+            (TokenType::Number,     1, 9, 9, 10),
+            (TokenType::Semicolon,  1, 10, 10, 11),
+            (TokenType::Print,      2, 3, 14, 19),
+            (TokenType::Identifier, 2, 9, 20, 22),
+            (TokenType::Semicolon,  2, 10, 22, 23),
+            (TokenType::Eof,        2, 11, 23, 23),
+        ];
+        for (t_type, line, column, start, end) in expected {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.t_type, t_type);
+            assert_eq!(token.span, Span { line, column, start, end });
+        }
+
+        // Ops from the same token share one run of debug info
+        let chunk = compiler::compile("print -(1 +\n 2);", &mut Heap::new()).unwrap();
+        assert_eq!(chunk.span_at(2), Span { line: 1, column: 11, start: 10, end: 11 });
+        assert_eq!(chunk.span_at(1).line, 2);
+        assert_eq!(chunk.span_at(3).column, 7);
+
+        // Errors point at the offending token
+        let src = "let x = 1;\nlet y = x + true;";
+        let mut vm = VM::new();
+        let err = vm.interpret(src).unwrap_err().remove(0);
+        assert_eq!(err.span(), Span { line: 2, column: 11, start: 21, end: 22 });
+        assert_eq!(&src[err.span().start..err.span().end], "+");
+
+        let src = "let x = 1\nprint x;";
+        let err = compiler::compile(src, &mut Heap::new()).unwrap_err().remove(0);
+        assert_eq!(&src[err.span().start..err.span().end], "print");
+        assert_eq!(err.span().line, 2);
+    }
+
+    #[test]
+    fn diagnostics() {
+        let src = "let x = 1;\nlet y = x + true;";
+        let err = VM::new().interpret(src).unwrap_err().remove(0);
+        let diagnostic = Diagnostic::from(&err);
+        assert_eq!(diagnostic.render("main.rlox", src, false), "\
+error[runtime]: type mismatch or invalid '+' operation.
+ --> main.rlox:2:11
+  |
+2 | let y = x + true;
+  |           ^
+  = note: left operand is Number, right operand is Bool
+  = help: '+' adds two numbers or joins two strings
+");
+
+        // The underline covers the whole token
+        let src = "{\n    let a = 1;\n    let a = 2;\n}";
+        let err = compiler::compile(src, &mut Heap::new()).unwrap_err().remove(0);
+        let rendered = Diagnostic::from(&err).render("main.rlox", src, false);
+        assert!(rendered.contains("3 |     let a = 2;\n  |         ^\n"));
+        assert!(rendered.contains("= help: "));
+
+        // Errors at the end of the source still point somewhere
+        let src = "print 1";
+        let err = compiler::compile(src, &mut Heap::new()).unwrap_err().remove(0);
+        let rendered = Diagnostic::from(&err).render("main.rlox", src, false);
+        assert!(rendered.contains("1 | print 1\n  |        ^\n"));
+
+        // Colour only when asked for
+        let rendered = Diagnostic::from(&err).render("main.rlox", src, true);
+        assert!(rendered.starts_with("\x1b[1;31merror[compile]"));
+    }
+
+    #[test]
+    fn recovery() {
+        // Independent errors are all reported, in source order
+        let src = "
+            let a = ;
+            print a
+            let b = 2;
+            fn f(x) {
+                let y = ;
+                return x;
+            }
+            struct S { fn m() { break; } }
+            if (true) { let c = @; }
+            let d = 4;
+        ";
+        let errors = compiler::compile(src, &mut Heap::new()).unwrap_err();
+        let kinds: Vec<String> = errors.iter().map(|e| e.to_string()).collect();
+        assert_eq!(errors.len(), 5, "{:#?}", kinds);
+        assert!(matches!(&errors[0], Error::Compile { kind: CompileErrorKind::ExpectedExpression, span } if span.line == 2));
+        assert!(matches!(&errors[1], Error::Compile { kind: CompileErrorKind::Expected(_), span } if span.line == 4));
+        assert!(matches!(&errors[2], Error::Compile { kind: CompileErrorKind::ExpectedExpression, span } if span.line == 6));
+        assert!(matches!(&errors[3], Error::Compile { kind: CompileErrorKind::BreakOutsideLoop, span } if span.line == 9));
+        assert!(matches!(&errors[4], Error::Lex { kind: LexErrorKind::UnknownCharacter('@'), span } if span.line == 10));
+
+        // A script with errors never runs
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("let x = 1; let y = ;").unwrap_err().len(), 1);
+        assert_eq!(vm.get_global("x"), None);
+
+        // The compiler is left in a usable state after recovering inside nested functions
+        let errors = compiler::compile("fn f() { fn g( { } } let z = 1 print z;", &mut Heap::new()).unwrap_err();
+        assert!(matches!(errors.first(), Some(Error::Compile { kind: CompileErrorKind::Expected("parameter name"), .. })));
+        assert!(matches!(errors.last(), Some(Error::Compile { kind: CompileErrorKind::Expected("'}' after block"), .. })));
+    }
+
+    #[test]
+    fn identifiers() {
+        // Keywords only match whole words
+        let src = "letter format iffy selfish fnord return_value while2 _ _tmp player_1 x2 café 变量 let";
+        let mut scanner = Scanner::new(src);
+        for expected in src.split(' ').take(13) {
+            let token = scanner.scan_token().unwrap();
+            assert_eq!(token.t_type, TokenType::Identifier);
+            assert_eq!(token.slice, expected);
+        }
+        assert_eq!(scanner.scan_token().unwrap().t_type, TokenType::Let);
+
+        // Digits can't start an identifier, letters after them make a malformed number
+        let mut scanner = Scanner::new("2x");
+        let token = scanner.scan_token().unwrap();
+        assert_eq!((token.t_type, token.slice), (TokenType::Number, "2x"));
+
+        let src = "
+            let player_1 = 3;
+            let _tmp = player_1 * 2;
+            let größe = _tmp + 1;
+        ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("größe"), Some(&Value::Number(7.0)));
+    }
+
+    #[test]
+    fn strings() {
+        let src = r##"
+            let escaped = "a\tb\n\"c\" \\ \u{1F600}\u{e9}";
+            let raw = r"C:\path\to\file";
+            let hashed = r#"say "hi" \n"#;
+            let lines = "one
+two";
+        "##;
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("escaped").unwrap().display(vm.heap()), "a\tb\n\"c\" \\ 😀é");
+        assert_eq!(vm.get_global("raw").unwrap().display(vm.heap()), r"C:\path\to\file");
+        assert_eq!(vm.get_global("hashed").unwrap().display(vm.heap()), r#"say "hi" \n"#);
+        assert_eq!(vm.get_global("lines").unwrap().display(vm.heap()), "one\ntwo");
+
+        // An identifier starting with r is still an identifier
+        let mut scanner = Scanner::new("rate r");
+        assert_eq!(scanner.scan_token().unwrap().slice, "rate");
+        assert_eq!(scanner.scan_token().unwrap().slice, "r");
+
+        // Bad escapes are reported at the escape, and the rest of the string is skipped
+        let src = r##"let a = "x\qy"; let b = "\u{110000}"; let c = "\u{1F6"; let d = "\u0041"; let e = r#"open";"##;
+        let errors = compiler::compile(src, &mut Heap::new()).unwrap_err();
+        let lex: Vec<(LexErrorKind, &str)> = errors.iter().map(|e| match e {
+            Error::Lex { kind, span } => (kind.clone(), &src[span.start..span.end]),
+            e => panic!("unexpected error {}", e),
+        }).collect();
+        assert_eq!(lex, [
+            (LexErrorKind::InvalidEscape(r"\q".to_string()), r"\q"),
+            (LexErrorKind::InvalidUnicodeEscape(r"\u{110000}".to_string()), r"\u{110000}"),
+            (LexErrorKind::InvalidUnicodeEscape(r"\u{1F6".to_string()), r"\u{1F6"),
+            (LexErrorKind::InvalidUnicodeEscape(r"\u".to_string()), r"\u"),
+            (LexErrorKind::UnterminatedString, r##"r#"open";"##),
+        ]);
+    }
+
+    #[test]
+    fn interpolation() {
+        let mut vm = VM::new();
+        let src = r#"
+            struct Player { hp = 7; }
+            let player = Player();
+            let max = 10;
+            let a = "hp: ${player.hp}/${max}";
+            let b = "${max > 5} ${nil}${"!"}";
+            let c = "outer ${"inner ${max * 2}"} done";
+            let d = "\${max}";
+            let e = "${max}";
+        "#;
+        vm.interpret(src).unwrap();
+        let global = |vm: &VM, name: &str| vm.get_global(name).unwrap().display(vm.heap());
+        assert_eq!(global(&vm, "a"), "hp: 7/10");
+        assert_eq!(global(&vm, "b"), "true nil!");
+        assert_eq!(global(&vm, "c"), "outer inner 20 done");
+        assert_eq!(global(&vm, "d"), "${max}");
+        assert_eq!(global(&vm, "e"), "10");
+
+        let errors = compiler::compile(r#"let f = "${}";"#, &mut Heap::new()).unwrap_err();
+        assert!(matches!(errors[0], Error::Compile { kind: CompileErrorKind::ExpectedExpression, .. }));
+    }
+
+    #[test]
+    fn numbers() {
+        let mut vm = VM::new();
+        vm.interpret("let a = 1_000; let b = 0xFF; let c = 0b1010; let d = 0o755; let e = 1e-9; let f = 2.5E+3; let g = .5e1; let h = 0x_dead_BEEF;").unwrap();
+        let number = |name: &str| match vm.get_global(name) {
+            Some(&Value::Number(n)) => n,
+            v => panic!("expected a number, got {:?}", v),
+        };
+        assert_eq!(number("a"), 1000.0);
+        assert_eq!(number("b"), 255.0);
+        assert_eq!(number("c"), 10.0);
+        assert_eq!(number("d"), 493.0);
+        assert_eq!(number("e"), 1e-9);
+        assert_eq!(number("f"), 2500.0);
+        assert_eq!(number("g"), 5.0);
+        assert_eq!(number("h"), 3735928559.0);
+
+        let src = "let a = 0b102; let b = 0x; let c = 1e+; let d = 12ab; let e = 0o7.5;";
+        let errors = compiler::compile(src, &mut Heap::new()).unwrap_err();
+        let kinds: Vec<(CompileErrorKind, &str)> = errors.iter().map(|e| match e {
+            Error::Compile { kind, span } => (kind.clone(), &src[span.start..span.end]),
+            e => panic!("unexpected error {}", e),
+        }).collect();
+        assert_eq!(kinds, [
+            (CompileErrorKind::InvalidDigit { digit: '2', radix: 2 }, "0b102"),
+            (CompileErrorKind::MissingDigits("0x".to_string()), "0x"),
+            (CompileErrorKind::MissingExponent("1e+".to_string()), "1e+"),
+            (CompileErrorKind::InvalidDigit { digit: 'a', radix: 10 }, "12ab"),
+            (CompileErrorKind::InvalidDigit { digit: '.', radix: 8 }, "0o7.5"),
+        ]);
+    }
+
+    #[test]
+    fn comments() {
+        let src = "
+            # Two line comments
+
+            # and a blank line between them
+            let a = 1; #[ a block #[ nested ]# comment ]# let b = #[ inline ]# 2;
+
+            ## Adds two numbers.
+            ##
+            ## Doc comments are kept for the next token.
+            fn add(x, y) { return x + y; }
+            let c = add(a, b); # trailing
+            ";
+        let mut vm = VM::new();
+        vm.interpret(src).unwrap();
+        assert_eq!(vm.get_global("c"), Some(&Value::Number(3.0)));
+
+        let mut scanner = Scanner::new(src);
+        let token = loop {
+            let token = scanner.scan_token().unwrap();
+            if token.t_type == TokenType::Fn { break token; }
+            assert_eq!(token.doc, None);
+        };
+        assert_eq!(token.doc_lines().collect::<Vec<_>>(), ["Adds two numbers.", "", "Doc comments are kept for the next token."]);
+        assert_eq!(scanner.scan_token().unwrap().doc, None);
+
+        let errors = compiler::compile("let a = 1; #[ open #[ nested ]#", &mut Heap::new()).unwrap_err();
+        assert!(matches!(&errors[..], [Error::Lex { kind: LexErrorKind::UnterminatedComment, span }] if span.start == 11));
+    }
+
+    #[test]
+    fn natives() {
+        fn sum(_: &mut VM, args: &[Value]) -> Result<Value, Error> {
+            args.iter().try_fold(Value::Number(0.0), |acc, &v| acc.add(v).map_err(|_| Error::native("sum takes numbers.")))
+        }
+
+        let mut vm = VM::new();
+        vm.register_native("sum", 3, sum);
+        vm.interpret("let f = sum; let a = f(1, 2, 3); print sum;").unwrap();
+        assert_eq!(vm.get_global("a"), Some(&Value::Number(6.0)));
+        assert_eq!(vm.get_global("f").unwrap().display(vm.heap()), "<native fn sum>");
+
+        let error = vm.interpret("let b = 1;\nlet c = sum(1,\n true, 3);").unwrap_err().remove(0);
+        assert_eq!(error, Error::Runtime {
+            kind: RuntimeErrorKind::Native("sum takes numbers.".to_string()),
+            span: Span { line: 2, column: 12, start: 22, end: 23 },
+        });
+        let error = vm.interpret("sum(1, 2);").unwrap_err().remove(0);
+        assert!(matches!(error, Error::Runtime { kind: RuntimeErrorKind::ArityMismatch { expected: 3, got: 2 }, .. }));
+    }
+
+    #[test]
+    fn conversions() {
+        let mut vm = VM::new();
+        vm.set_stress_gc(true);
+        vm.register_fn("hypot", |a: f64, b: f64| a.hypot(b));
+        vm.register_fn("repeat", |s: String, n: i64| vec![s; n as usize]);
+        vm.register_fn("count", |items: Vec<Option<String>>| items.iter().flatten().count() as i64);
+        vm.register_fn("scores", || std::collections::HashMap::from([("ann".to_string(), 3.0), ("bo".to_string(), 5.0)]));
+        vm.register_fn("total", |scores: std::collections::HashMap<String, f64>| scores.values().sum::<f64>());
+        vm.register_native("shout", 1, |vm, args| {
+            let s = <&str>::from_value(args[0], vm.heap()).map_err(|kind| Error::Runtime { kind, span: Span::default() })?;
+            Ok(s.to_uppercase().into_value(vm))
+        });
+        vm.interpret(r#"
+            struct P { x = 1; y = 2; }
+            let h = hypot(3, 4);
+            let r = repeat(shout("ab"), 3);
+            let c = count(r);
+            let s = scores();
+            let t = total(s) + total(P());
+        "#).unwrap();
+        let global = |vm: &VM, name: &str| vm.get_global(name).unwrap().display(vm.heap());
+        assert_eq!(global(&vm, "h"), "5");
+        assert_eq!(global(&vm, "r"), "[AB, AB, AB]");
+        assert_eq!(global(&vm, "c"), "3");
+        assert_eq!(global(&vm, "s"), "{ann: 3, bo: 5}");
+        assert_eq!(global(&vm, "t"), "11");
+        assert_eq!(Vec::<String>::from_value(*vm.get_global("r").unwrap(), vm.heap()), Ok(vec!["AB".to_string(); 3]));
+
+        let error = vm.interpret("hypot(3, \"4\");").unwrap_err().remove(0);
+        assert_eq!(error.to_string(), "Runtime error, at line 1: argument 2 of 'hypot' expected Number but got Str.");
+        let error = vm.interpret("repeat(\"a\", 1.5);").unwrap_err().remove(0);
+        assert!(matches!(error, Error::Runtime { kind: RuntimeErrorKind::InvalidArgument { index: 2, expected: "Integer", .. }, .. }));
+        let error = vm.interpret("hypot(1);").unwrap_err().remove(0);
+        assert!(matches!(error, Error::Runtime { kind: RuntimeErrorKind::ArityMismatch { expected: 2, got: 1 }, .. }));
+    }
+
+    #[test]
+    fn userdata() {
+        use std::{cell::Cell, rc::Rc};
+        use crate::value::UserData;
+
+        struct Entity { hp: f64, tag: Value, finalized: Rc<Cell<usize>> }
+        impl UserData for Entity {
+            fn type_name(&self) -> &'static str { "Entity" }
+            fn trace(&self, values: &mut Vec<Value>) { values.push(self.tag); }
+            fn finalize(&mut self) { self.finalized.set(self.finalized.get() + 1); }
+        }
+
+        let mut vm = VM::new();
+        vm.register_method::<Entity>("heal", 1, |vm, args| {
+            let amount = f64::from_value(args[1], vm.heap()).map_err(|kind| Error::Runtime { kind, span: Span::default() })?;
+            Ok(vm.with_userdata(args[0], |e: &mut Entity| { e.hp += amount; Value::Number(e.hp) }).unwrap())
+        });
+        vm.register_getter::<Entity>("hp", |vm, this| Ok(Value::Number(vm.with_userdata(this, |e: &mut Entity| e.hp).unwrap())));
+        vm.register_setter::<Entity>("hp", |vm, this, value| {
+            let hp = f64::from_value(value, vm.heap()).map_err(|kind| Error::Runtime { kind, span: Span::default() })?;
+            vm.with_userdata(this, |e: &mut Entity| e.hp = hp);
+            Ok(())
+        });
+        vm.register_getter::<Entity>("tag", |vm, this| Ok(vm.with_userdata(this, |e: &mut Entity| e.tag).unwrap()));
+        vm.register_setter::<Entity>("tag", |vm, this, value| {
+            vm.with_userdata(this, |e: &mut Entity| e.tag = value);
+            Ok(())
+        });
+
+        let finalized = Rc::new(Cell::new(0));
+        let entity = vm.new_userdata(Entity { hp: 10.0, tag: Value::Nil, finalized: finalized.clone() });
+        vm.set_global("p", entity);
+        vm.interpret(r#"
+            p.heal(5);
+            let heal = p.heal;
+            let after = heal(2);
+            p.hp = p.hp * 2;
+            p.tag = "hero" + "!";
+        "#).unwrap();
+        vm.collect_garbage();
+        assert_eq!(vm.get_global("after"), Some(&Value::Number(17.0)));
+        assert_eq!(vm.interpret("p.hp;").map(|_| ()), Ok(()));
+        assert_eq!(vm.with_userdata(entity, |e: &mut Entity| (e.hp, e.tag.display(vm.heap()))), Some((34.0, "hero!".to_string())));
+        assert_eq!(vm.get_global("p").unwrap().display(vm.heap()), "<Entity userdata>");
+
+        // A getter returning a function can be called directly, as with a field
+        vm.register_native("double", 1, |vm, args| Ok(Value::Number(f64::from_value(args[0], vm.heap()).unwrap_or(0.0) * 2.0)));
+        vm.register_getter::<Entity>("callback", |vm, _| Ok(*vm.get_global("double").unwrap()));
+        assert_eq!(vm.interpret("let f = p.callback; f(2) + p.callback(3)"), Ok(Value::Number(10.0)));
+
+        let error = vm.interpret("p.mana;").unwrap_err().remove(0);
+        assert!(matches!(error, Error::Runtime { kind: RuntimeErrorKind::UndefinedProperty(_), .. }));
+        let error = vm.interpret("p.hp = \"full\";").unwrap_err().remove(0);
+        assert!(matches!(error, Error::Runtime { kind: RuntimeErrorKind::TypeMismatch { expected: "Number", got: "Str" }, .. }));
+
+        // Only finalized once nothing references it
+        assert_eq!(finalized.get(), 0);
+        vm.interpret("p = nil; heal = nil;").unwrap();
+        vm.collect_garbage();
+        assert_eq!(finalized.get(), 1);
+    }
+
+    #[test]
+    fn io() {
+        use std::{cell::RefCell, io::{self, Cursor, Write}, rc::Rc};
+
+        #[derive(Clone, Default)]
+        struct Capture(Rc<RefCell<Vec<u8>>>);
+        impl Write for Capture {
+            fn write(&mut self, buf: &[u8]) -> io::Result<usize> { self.0.borrow_mut().write(buf) }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+        struct Broken;
+        impl Write for Broken {
+            fn write(&mut self, _: &[u8]) -> io::Result<usize> { Err(io::Error::other("closed")) }
+            fn flush(&mut self) -> io::Result<()> { Ok(()) }
+        }
+
+        let output = Capture::default();
+        let mut vm = VM::with_io(output.clone(), Cursor::new("alice\nbob\n"));
+        vm.register_native("read", 0, |vm, _| {
+            let line = vm.read_line().map_err(|e| Error::native(e.to_string()))?;
+            Ok(line.map(|l| l.trim_end().to_string()).into_value(vm))
+        });
+        vm.interpret(r#"print "hi " + read(); print read(); print read(); print 1 + 2;"#).unwrap();
+        assert_eq!(String::from_utf8(output.0.borrow().clone()).unwrap(), "hi alice\nbob\nnil\n3\n");
+
+        let swapped = Capture::default();
+        vm.set_output(swapped.clone());
+        vm.interpret("print true;").unwrap();
+        assert_eq!(swapped.0.borrow().as_slice(), b"true\n");
+        assert_eq!(output.0.borrow().len(), 19);
+
+        vm.set_output(Broken);
+        let error = vm.interpret("print 1;").unwrap_err().remove(0);
+        assert_eq!(error.to_string(), "Runtime error, at line 1: failed to write output: closed.");
+    }
+
+    #[test]
+    fn results() {
+        let mut vm = VM::new();
+        assert_eq!(vm.interpret("1 + 2"), Ok(Value::Number(3.0)));
+        assert_eq!(vm.interpret("let x = 4; x * 2;"), Ok(Value::Number(8.0)));
+        // Only an expression ending the script is its result
+        assert_eq!(vm.interpret("x; let y = 1;"), Ok(Value::Nil));
+        assert_eq!(vm.interpret("print x;"), Ok(Value::Nil));
+        assert_eq!(vm.interpret("{ x + 1; }"), Ok(Value::Nil));
+        // Single statement bodies ending the script run as usual and aren't its result
+        assert_eq!(vm.interpret("let c = 0; while (c < 3) c = c + 1;"), Ok(Value::Nil));
+        assert_eq!(vm.interpret("c"), Ok(Value::Number(3.0)));
+        assert_eq!(vm.interpret("let d = 0; if (c == 3) d = 1;"), Ok(Value::Nil));
+        assert_eq!(vm.interpret("d"), Ok(Value::Number(1.0)));
+
+        let config = "
+            let width = 640;
+            fn scale(n) { return n * 2; }
+            \"${scale(width)}x${width}\"
+        ";
+        assert_eq!(vm.eval::<String>(config), Ok("1280x640".to_string()));
+        assert_eq!(vm.eval::<Option<f64>>("nil"), Ok(None));
+        let errors = vm.eval::<bool>("width").unwrap_err();
+        assert!(matches!(errors[..], [Error::Runtime { kind: RuntimeErrorKind::TypeMismatch { expected: "Bool", got: "Number" }, .. }]));
+
+        // A missing ';' is still an error anywhere but the end of the script
+        let errors = compiler::compile("1 + 2 let z = 3;", &mut Heap::new()).unwrap_err();
+        assert!(matches!(errors[0], Error::Compile { kind: CompileErrorKind::Expected("';' after expression"), .. }));
+        let errors = compiler::compile("fn f() { 1 }", &mut Heap::new()).unwrap_err();
+        assert!(matches!(errors[0], Error::Compile { kind: CompileErrorKind::Expected("';' after expression"), .. }));
+    }
 }
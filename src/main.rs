@@ -1,23 +1,32 @@
-use std::{env, fs, io::{self, Write}};
+use std::{env, fs, io::{self, IsTerminal, Write}};
 
-use rlox::{vm::VM};
+use rlox::{Error, diagnostic::Diagnostic, value::Value, vm::VM};
+
+fn report(errors: &[Error], file: &str, source: &str) {
+    // Diagnostics go to stderr, so only colour them when that is a terminal.
+    for e in errors {
+        eprint!("{}", Diagnostic::from(e).render(file, source, io::stderr().is_terminal()));
+    }
+}
 
 fn repl(vm: &mut VM) -> io::Result<()> {
-    let mut line = String::new();
     loop {
         print!("//rlox> ");
         io::stdout().flush().unwrap();
-        line.clear();
 
-        match io::stdin().read_line(&mut line) {
-            Ok(0) => {
+        match vm.read_line() {
+            Ok(None) => {
                 println!();
                 return Ok(());
             }
-            Ok(_) => {
-                if let Err(e) = vm.interpret(line.as_str()) {
-                    eprintln!("{}", e);
+            Ok(Some(line)) => match vm.interpret(line.as_str()) {
+                // Echo the value of a bare expression, statements leave nil
+                Ok(Value::Nil) => (),
+                Ok(value) => {
+                    let echo = value.display(vm.heap());
+                    writeln!(vm.output(), "{}", echo)?;
                 }
+                Err(e) => report(&e, "<repl>", &line),
             }
             Err(e) => {
                 return Err(e);
@@ -28,7 +37,10 @@ fn repl(vm: &mut VM) -> io::Result<()> {
 
 fn run_file(vm: &mut VM, path: &str) {
     let content = fs::read_to_string(path).unwrap();
-    vm.interpret(content.as_str()).unwrap();
+    if let Err(e) = vm.interpret(content.as_str()) {
+        report(&e, path, &content);
+        std::process::exit(1);
+    }
 }
 
 fn main() {
@@ -1,11 +1,25 @@
+use std::{iter::Peekable, str::CharIndices};
+
 use phf::phf_map;
+use unicode_ident::{is_xid_continue, is_xid_start};
+
+use crate::error::{Error, LexErrorKind, Span};
 
 pub struct Scanner<'a> {
     source: &'a str,
+    chars:  Peekable<CharIndices<'a>>,
     pub line:   usize,
+    column: usize,
+    // Byte offsets of the token being scanned
     start:  usize,
     end:    usize,
-    pub err:    String,
+    // Position of the char at start
+    start_line:   usize,
+    start_column: usize,
+    // One entry per interpolated expression being scanned, counting the braces opened inside it.
+    interpolations: Vec<usize>,
+    // Byte range of the '##' doc comments before the token being scanned
+    doc: Option<(usize, usize)>,
 }
 
 #[derive(Clone, Copy, Debug, PartialEq)]
@@ -21,12 +35,15 @@ pub enum TokenType {
     
     // Literals
     Identifier, Str, Number,
+    // Segments of an interpolated string, the first and middle ones end with '${' and the last starts with '}'
+    Interpolation, InterpolationEnd,
 
     // Keywords
     And, Struct, Else, False,
     For, Fn, If, Nil, Or,
     Print, Return, Super, StructSelf,
     True, Let,
+    While, Break, Continue,
     
     Eof,
 }
@@ -35,17 +52,28 @@ pub enum TokenType {
 pub struct Token<'a> {
     pub t_type: TokenType,
     pub slice: &'a str,
-    pub line:  usize,
+    pub span:  Span,
+    // The '##' doc comments right before the token, from the first to the end of the last, as in the source.
+    pub doc:   Option<&'a str>,
 }
 
 impl<'a> Token<'a> {
-    fn new(t: TokenType, slice: &'a str, line: usize) -> Self {
+    fn new(t: TokenType, slice: &'a str, span: Span, doc: Option<&'a str>) -> Self {
         Self {
             t_type: t,
             slice,
-            line,
+            span,
+            doc,
         }
     }
+
+    // The text of each doc comment line, without the '##' and one space after it.
+    pub fn doc_lines(&self) -> impl Iterator<Item = &'a str> {
+        self.doc.into_iter()
+            .flat_map(str::lines)
+            .filter_map(|line| line.trim_start().strip_prefix("##"))
+            .map(|line| line.strip_prefix(' ').unwrap_or(line))
+    }
 }
 
 impl<'a> Scanner<'a> {
@@ -65,46 +93,61 @@ impl<'a> Scanner<'a> {
         "self"   => TokenType::StructSelf,
         "true"   => TokenType::True,
         "let"    => TokenType::Let,
+        "while"  => TokenType::While,
+        "break"  => TokenType::Break,
+        "continue" => TokenType::Continue,
     };
 
     pub fn new(source: &'a str) -> Self {
         Self {
             source,
-            line:  1,
+            chars:  source.char_indices().peekable(),
+            line:   1,
+            column: 1,
 
-            start: 0,
-            end:   1,
-            err:   String::new(),
-        } 
+            start:  0,
+            end:    0,
+            start_line:   1,
+            start_column: 1,
+            interpolations: Vec::new(),
+            doc: None,
+        }
     }
-    
+
     // Gets current slices encompassed by start - end
-    pub fn get_slice(&self) -> Option<&'a str> {
-        let (start, _) = self.source.char_indices().nth(self.start)?;
-        let (end, _)   = self.source.char_indices().nth(self.end)
-            .unwrap_or((self.source.len(), '\0'));
-         
-        self.source.get(start..end)
+    pub fn get_slice(&self) -> &'a str {
+        &self.source[self.start..self.end]
     }
 
-    fn get_current(&self) -> Option<char> {
-        self.source.chars().nth(self.end - 1)
+    fn get_span(&self) -> Span {
+        Span {
+            line:   self.start_line,
+            column: self.start_column,
+            start:  self.start,
+            end:    self.end,
+        }
     }
 
-    fn peek(&self) -> Option<char> {
-        self.source.chars().nth(self.end)
+    fn peek(&mut self) -> Option<char> {
+        self.chars.peek().map(|&(_, c)| c)
     }
 
-    fn consume(&mut self) {
-        self.end += 1;
+    // Consumes the next char, keeping track of lines and columns.
+    fn consume(&mut self) -> Option<char> {
+        let (idx, c) = self.chars.next()?;
+        self.end = idx + c.len_utf8();
+        if c == '\n' {
+            self.line  += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
     }
 
     // Peeks next char, if match consumes it
     fn match_and_consume(&mut self, c: char) -> bool {
-        if let Some(peek) = self.peek() && peek == c {
-            self.consume();
-            true
-        } else { false }
+        self.consume_if(|peek| peek == c)
     }
 
     // Peeks next char and consumes if condition is true
@@ -115,126 +158,270 @@ impl<'a> Scanner<'a> {
         } else { false }
     }
 
+    // Consumes chars until f matches the next one, returning false if the source ran out first.
     fn consume_till(&mut self, f: impl Fn(char) -> bool) -> bool {
         loop {
-            if let Some(peek) = self.peek() {
-                if f(peek) { return true }
-                self.consume();
-            } else { return false }
-        }
-    }
-
-    fn consume_till_mut(&mut self, f: &mut impl FnMut(char) -> bool) -> bool {
-        loop {
-            if let Some(peek) = self.peek() {
-                if f(peek) { return true }
-                self.consume();
-            } else { return false }
+            match self.peek() {
+                Some(peek) if f(peek) => return true,
+                Some(_) => { self.consume(); }
+                None => return false,
+            }
         }
     }
 
+    // Starts the next token at the next char.
     fn next_range(&mut self) {
         self.start = self.end;
-        self.end  += 1;
-    }
-
-    fn emit_token(&mut self, t: TokenType) -> Option<Token<'a>> {
-        if let Some(slice) = self.get_slice() {
-            self.next_range();
-            Some(Token::new(t,
-                slice,
-                self.line
-            ))
-        } else { None }
-    }
-
-    pub fn scan_token(&mut self) -> Option<Token<'a>> {
-        // Skip whitespace
-        {
-            let mut line_change: usize = 0;
-            if self.get_current().unwrap_or('\0').is_whitespace() {
-                self.consume_till_mut(&mut |c| {
-                    if c == '\n' {
-                        line_change += 1;
+        self.start_line   = self.line;
+        self.start_column = self.column;
+    }
+
+    fn emit_token(&mut self, t: TokenType) -> Result<Token<'a>, Error> {
+        let doc = self.doc.take().map(|(start, end)| &self.source[start..end]);
+        Ok(Token::new(t, self.get_slice(), self.get_span(), doc))
+    }
+
+    fn error(&mut self, kind: LexErrorKind) -> Result<Token<'a>, Error> {
+        Err(Error::Lex { kind, span: self.get_span() })
+    }
+
+    // Consumes chars until end reaches the byte offset target.
+    fn consume_to(&mut self, target: usize) {
+        while self.end < target && self.consume().is_some() {}
+    }
+
+    // Scans the rest of a string, checking its escapes. The compiler decodes them with unescape.
+    // A '${' ends the token early as an interpolation, and scanning resumes after the matching '}'.
+    fn string(&mut self, end: TokenType) -> Result<Token<'a>, Error> {
+        let mut error = None;
+        let t = loop {
+            match self.consume() {
+                None => return self.error(LexErrorKind::UnterminatedString),
+                Some('"') => break end,
+                Some('$') if self.match_and_consume('{') => {
+                    self.interpolations.push(0);
+                    break TokenType::Interpolation;
+                }
+                Some('\\') => {
+                    let (start, line, column) = (self.end - 1, self.line, self.column - 1);
+                    let (result, len) = match decode_escape(&self.source[self.end..]) {
+                        Ok((_, len)) => (None, len),
+                        Err((kind, len)) => (Some(kind), len),
+                    };
+                    self.consume_to(self.end + len);
+
+                    // Keep scanning to the closing quote, so the rest of the string isn't read as code.
+                    if let Some(kind) = result && error.is_none() {
+                        error = Some(Error::Lex { kind, span: Span { line, column, start, end: self.end } });
                     }
-                    !c.is_whitespace()
-                });
-                self.next_range();
+                }
+                Some(_) => (),
             }
-            self.line += line_change;
+        };
+
+        match error {
+            Some(e) => Err(e),
+            None => self.emit_token(t),
         }
-        // Skip comments
-        if self.get_current().unwrap_or('\0') == '#' {
-            self.consume_till(|c| {
-                c == '\n'
-            });
-            self.next_range();
+    }
+
+    // The number of '#'s after the 'r' just consumed, if it starts a raw string like r"..." or r#"..."#.
+    fn raw_string_hashes(&self) -> Option<usize> {
+        let rest = &self.source[self.end..];
+        let hashes = rest.len() - rest.trim_start_matches('#').len();
+        rest[hashes..].starts_with('"').then_some(hashes)
+    }
+
+    // Scans a raw string, which has no escapes and ends at a quote followed by as many '#'s as it started with.
+    fn raw_string(&mut self) -> Result<Token<'a>, Error> {
+        let hashes = self.raw_string_hashes().unwrap();
+        self.consume_to(self.end + hashes + 1);
+
+        let terminator = format!("\"{}", "#".repeat(hashes));
+        match self.source[self.end..].find(&terminator) {
+            Some(len) => {
+                self.consume_to(self.end + len + terminator.len());
+                self.emit_token(TokenType::Str)
+            }
+            None => {
+                self.consume_to(self.source.len());
+                self.error(LexErrorKind::UnterminatedString)
+            }
         }
+    }
 
-        match self.get_current() {
-            None => Some(Token::new(TokenType::Eof, "eof", 0)),
-            Some(curr) => match curr {
-                '(' => self.emit_token(TokenType::LParen),
-                ')' => self.emit_token(TokenType::RParen),
-                '{' => self.emit_token(TokenType::LBrace),
-                '}' => self.emit_token(TokenType::RBrace),
-                ',' => self.emit_token(TokenType::Comma),
-                // Check if next char is a digit, to account for float syntax '.5'
-                '.' => if self.consume_if(|c| c.is_digit(10)) { self.consume_till(|c| !c.is_digit(10)); self.emit_token(TokenType::Number)}
-                       else { self.emit_token(TokenType::Dot) }
-                ';' => self.emit_token(TokenType::Semicolon),
-                '+' => self.emit_token(TokenType::Plus),
-                '-' => self.emit_token(TokenType::Minus),
-                '*' => self.emit_token(TokenType::Star),
-                '/' => self.emit_token(TokenType::Slash),
-                
-                '!' => if self.match_and_consume('=') { self.emit_token(TokenType::BangEqual)   }
-                       else {                           self.emit_token(TokenType::Bang)        }
-                '=' => if self.match_and_consume('=') { self.emit_token(TokenType::Equate)      }    
-                       else {                           self.emit_token(TokenType::Equal)       }
-                '>' => if self.match_and_consume('=') { self.emit_token(TokenType::GreaterEq)   }
-                       else {                           self.emit_token(TokenType::GreaterThan) }
-                '<' => if self.match_and_consume('=') { self.emit_token(TokenType::LessEq)      }    
-                       else {                           self.emit_token(TokenType::LessThan)    }
-                
-                '"' => {
-                    let mut change: usize = 0;
-                    let result = self.consume_till_mut(&mut |c| {
-                        if c == '\n' { change += 1; }
-                        c == '"'
-                    });
-                    self.line += change;
-
-                    if !result {
-                        self.err = "unterminated string".to_string();
-                        None 
-                    } else { 
-                        self.consume(); // Consume terminating quote
-                        self.emit_token(TokenType::Str) 
+    // Skips any mix of whitespace, '#' line comments, '##' doc comments and nested '#[ ... ]#' block comments.
+    fn skip_whitespace(&mut self) -> Result<(), Error> {
+        self.doc = None;
+        loop {
+            match self.peek() {
+                Some(c) if c.is_whitespace() => { self.consume(); }
+                Some('#') => {
+                    let start = self.end;
+                    self.next_range();
+                    self.consume();
+                    if self.match_and_consume('[') {
+                        self.block_comment()?;
+                    } else {
+                        let doc = self.peek() == Some('#');
+                        self.consume_till(|c| c == '\n');
+                        if doc {
+                            self.doc = Some((self.doc.map_or(start, |(start, _)| start), self.end));
+                        }
                     }
                 }
+                _ => return Ok(()),
+            }
+        }
+    }
 
-                _ => {
-                    if curr.is_digit(10) {
-                        let mut has_dot = false;
-                        self.consume_till_mut(&mut |c| {
-                            let check = !c.is_digit(10) && (c != '.' || has_dot) && c != '_';
-                            if c == '.' { has_dot = true; }
-                            check
-                        });
-                        self.emit_token(TokenType::Number)
-                    } else if curr.is_alphabetic() {
-                        self.consume_till(|c|{ !c.is_alphabetic() });
-
-                        if let Some(word) = Self::KEYWORDS.get(self.get_slice().unwrap_or("")) {
-                            self.emit_token(*word)
-                        } else { self.emit_token(TokenType::Identifier) }
-                    } else {
-                        self.err = "unknown character".to_string();
-                        None
-                    }
+    // Skips the rest of a block comment, including any nested in it.
+    fn block_comment(&mut self) -> Result<(), Error> {
+        let mut depth = 1;
+        while depth > 0 {
+            match self.consume() {
+                None => return Err(Error::Lex { kind: LexErrorKind::UnterminatedComment, span: self.get_span() }),
+                Some('#') if self.match_and_consume('[') => depth += 1,
+                Some(']') if self.match_and_consume('#') => depth -= 1,
+                Some(_) => (),
+            }
+        }
+        Ok(())
+    }
+
+    // Scans the rest of a number literal, leaving the compiler to check its digits.
+    // Letters and '_' are taken too, so 0xFF, 1e-9 and malformed literals like 12ab are a single token.
+    fn number(&mut self) -> Result<Token<'a>, Error> {
+        let decimal = !(self.get_slice() == "0" && matches!(self.peek(), Some('x' | 'X' | 'b' | 'B' | 'o' | 'O')));
+        let mut has_dot = self.get_slice().starts_with('.');
+        let mut prev = ' ';
+        while let Some(c) = self.peek() {
+            let exponent_sign = decimal && matches!(c, '+' | '-') && matches!(prev, 'e' | 'E');
+            if c == '.' && !has_dot {
+                has_dot = true;
+            } else if !c.is_ascii_alphanumeric() && c != '_' && !exponent_sign { break }
+            prev = c;
+            self.consume();
+        }
+        self.emit_token(TokenType::Number)
+    }
+
+    pub fn scan_token(&mut self) -> Result<Token<'a>, Error> {
+        self.skip_whitespace()?;
+        self.next_range();
+
+        let Some(curr) = self.consume() else {
+            return self.emit_token(TokenType::Eof);
+        };
+
+        match curr {
+            '(' => self.emit_token(TokenType::LParen),
+            ')' => self.emit_token(TokenType::RParen),
+            '{' => {
+                if let Some(depth) = self.interpolations.last_mut() { *depth += 1 }
+                self.emit_token(TokenType::LBrace)
+            }
+            '}' => match self.interpolations.last_mut() {
+                Some(0) => {
+                    self.interpolations.pop();
+                    self.string(TokenType::InterpolationEnd)
+                }
+                Some(depth) => {
+                    *depth -= 1;
+                    self.emit_token(TokenType::RBrace)
+                }
+                None => self.emit_token(TokenType::RBrace),
+            }
+            ',' => self.emit_token(TokenType::Comma),
+            // Check if next char is a digit, to account for float syntax '.5'
+            '.' => if self.consume_if(|c| c.is_ascii_digit()) { self.number() }
+                   else { self.emit_token(TokenType::Dot) }
+            ';' => self.emit_token(TokenType::Semicolon),
+            '+' => self.emit_token(TokenType::Plus),
+            '-' => self.emit_token(TokenType::Minus),
+            '*' => self.emit_token(TokenType::Star),
+            '/' => self.emit_token(TokenType::Slash),
+            
+            '!' => if self.match_and_consume('=') { self.emit_token(TokenType::BangEqual)   }
+                   else {                           self.emit_token(TokenType::Bang)        }
+            '=' => if self.match_and_consume('=') { self.emit_token(TokenType::Equate)      }    
+                   else {                           self.emit_token(TokenType::Equal)       }
+            '>' => if self.match_and_consume('=') { self.emit_token(TokenType::GreaterEq)   }
+                   else {                           self.emit_token(TokenType::GreaterThan) }
+            '<' => if self.match_and_consume('=') { self.emit_token(TokenType::LessEq)      }    
+                   else {                           self.emit_token(TokenType::LessThan)    }
+            
+            '"' => self.string(TokenType::Str),
+            'r' if self.raw_string_hashes().is_some() => self.raw_string(),
+
+            _ => {
+                if curr.is_ascii_digit() {
+                    self.number()
+                } else if curr == '_' || is_xid_start(curr) {
+                    self.consume_till(|c| !is_xid_continue(c));
+
+                    if let Some(word) = Self::KEYWORDS.get(self.get_slice()) {
+                        self.emit_token(*word)
+                    } else { self.emit_token(TokenType::Identifier) }
+                } else {
+                    self.error(LexErrorKind::UnknownCharacter(curr))
                 }
             }
         }
     }
 }
+
+// Decodes the escape sequence following a '\', returning the char and how many bytes of s it took.
+// Errors carry the number of bytes making up the invalid escape.
+pub fn decode_escape(s: &str) -> Result<(char, usize), (LexErrorKind, usize)> {
+    let Some(c) = s.chars().next() else {
+        return Err((LexErrorKind::UnterminatedString, 0));
+    };
+
+    let decoded = match c {
+        'n'  => '\n',
+        't'  => '\t',
+        'r'  => '\r',
+        '0'  => '\0',
+        '\\' => '\\',
+        '"'  => '"',
+        '$'  => '$',
+        '\'' => '\'',
+        'u'  => return decode_unicode_escape(s),
+        _ => return Err((LexErrorKind::InvalidEscape(format!("\\{}", c)), c.len_utf8())),
+    };
+    Ok((decoded, 1))
+}
+
+// Decodes 'u{X}', where X is 1 to 6 hex digits naming a unicode scalar value.
+fn decode_unicode_escape(s: &str) -> Result<(char, usize), (LexErrorKind, usize)> {
+    let Some(braced) = s[1..].strip_prefix('{') else {
+        return Err((LexErrorKind::InvalidUnicodeEscape("\\u".to_string()), 1));
+    };
+    let digits = braced.len() - braced.trim_start_matches(|c: char| c.is_ascii_hexdigit()).len();
+    let closed = braced[digits..].starts_with('}');
+    let len = if closed { digits + 3 } else { digits + 2 };
+
+    let decoded = if closed && (1..=6).contains(&digits) {
+        u32::from_str_radix(&s[2..2 + digits], 16).ok().and_then(char::from_u32)
+    } else { None };
+
+    match decoded {
+        Some(c) => Ok((c, len)),
+        None => Err((LexErrorKind::InvalidUnicodeEscape(format!("\\{}", &s[..len])), len)),
+    }
+}
+
+// Decodes the escapes in the body of a string the scanner has already checked.
+pub fn unescape(body: &str) -> String {
+    let mut out = String::with_capacity(body.len());
+    let mut rest = body;
+    while let Some(i) = rest.find('\\') {
+        out.push_str(&rest[..i]);
+        let (c, len) = decode_escape(&rest[i + 1..]).expect("Expected escapes to be checked by the scanner.");
+        out.push(c);
+        rest = &rest[i + 1 + len..];
+    }
+    out.push_str(rest);
+    out
+}
@@ -27,11 +27,26 @@ impl<T: Clone> KeyedArray<T> {
     }
 
     pub fn remove(&mut self, id: usize) {
-        if id >= self.array.len() { return }
+        if id >= self.array.len() || self.array[id].is_none() { return }
         self.array[id] = None;
         self.free_locations.push_front(id);
     }
 
+    pub fn get(&self, id: usize) -> Option<&T> {
+        self.array.get(id)?.as_ref()
+    }
+
+    pub fn get_mut(&mut self, id: usize) -> Option<&mut T> {
+        self.array.get_mut(id)?.as_mut()
+    }
+
+    // Iterates over allocated IDs and their values.
+    pub fn iter(&self) -> impl Iterator<Item = (usize, &T)> {
+        self.array.iter()
+            .enumerate()
+            .filter_map(|(id, value)| Some((id, value.as_ref()?)))
+    }
+
     pub fn len(&self) -> usize {
         self.array.len()
     }
@@ -1,37 +1,199 @@
-use std::rc::Rc;
+use std::{any::Any, cell::RefCell, collections::HashMap, fmt, rc::Rc};
 
-#[derive(Debug, Clone, PartialEq)]
+use crate::{error::{Error, RuntimeErrorKind}, heap::{Heap, Obj, ObjRef}, vm::{Chunk, VM}};
+
+// A function implemented by the host, called with its arguments still on the VM's stack.
+pub type NativeFn = Rc<dyn Fn(&mut VM, &[Value]) -> Result<Value, Error>>;
+
+#[derive(Clone)]
+pub struct Native {
+    pub name:  String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+impl fmt::Debug for Native {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Native").field("name", &self.name).field("arity", &self.arity).finish_non_exhaustive()
+    }
+}
+
+// Property accessors for a UserData type, given the object they were accessed on.
+pub type Getter = Rc<dyn Fn(&mut VM, Value) -> Result<Value, Error>>;
+pub type Setter = Rc<dyn Fn(&mut VM, Value, Value) -> Result<(), Error>>;
+
+// A host object handed to scripts, like a game engine entity.
+// Its methods and properties are registered on the VM per Rust type, see VM::register_method.
+pub trait UserData: Any {
+    fn type_name(&self) -> &'static str;
+
+    // Pushes every value the object holds, so they live as long as it does.
+    fn trace(&self, _values: &mut Vec<Value>) {}
+
+    // Called once scripts drop their last reference, just before the object is freed.
+    fn finalize(&mut self) {}
+}
+
+// The heap's handle to a host object, shared so its methods can borrow it while the VM runs.
+#[derive(Clone)]
+pub struct UserDataCell(pub Rc<RefCell<dyn UserData>>);
+
+impl UserDataCell {
+    pub fn type_name(&self) -> &'static str {
+        self.0.borrow().type_name()
+    }
+}
+
+impl fmt::Debug for UserDataCell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "UserData({})", self.type_name())
+    }
+}
+
+// Where a closure finds a captured variable when it is created:
+// a local slot of the enclosing function, or one of the enclosing closure's upvalues.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct UpvalueDesc {
+    pub is_local: bool,
+    pub index: usize,
+}
+
+#[derive(Debug, Clone)]
+pub struct Function {
+    pub name:  Option<String>, // None for the top-level script
+    pub arity: usize,
+    pub upvalues: Vec<UpvalueDesc>,
+    pub chunk: Chunk,
+}
+
+impl Function {
+    pub fn new(name: Option<&str>) -> Self {
+        Self {
+            name: name.map(|n| n.to_string()),
+            arity: 0,
+            upvalues: Vec::new(),
+            chunk: Chunk::new(),
+        }
+    }
+
+    fn display(&self) -> String {
+        match &self.name {
+            Some(name) => format!("<fn {}>", name),
+            None => "<script>".to_string(),
+        }
+    }
+}
+
+// A captured variable. It stays on the stack while open, and moves into the upvalue once closed.
+#[derive(Debug, Clone)]
+pub enum Upvalue {
+    Open(usize), // Stack index
+    Closed(Value),
+}
+
+#[derive(Debug, Clone)]
+pub struct Closure {
+    pub function: ObjRef,
+    pub upvalues: Vec<ObjRef>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Struct {
+    pub name: ObjRef,
+    // Both keyed by interned name, fields in declaration order with a closure computing each default, or None for nil
+    pub fields:  Vec<(ObjRef, Option<ObjRef>)>,
+    pub methods: HashMap<ObjRef, ObjRef>,
+}
+
+impl Struct {
+    pub fn new(name: ObjRef) -> Self {
+        Self {
+            name,
+            fields:  Vec::new(),
+            methods: HashMap::new(),
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Instance {
+    pub structure: ObjRef,
+    pub fields: HashMap<ObjRef, Value>,
+}
+
+// A method closure paired with the instance it was accessed from.
+#[derive(Debug, Clone)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: ObjRef,
+}
+
+// Objects are referenced by handles into the VM's heap, so values are cheap to copy.
+// Handles compare by identity, which for strings is content equality as they are interned.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     Nil,
     Number(f64),
     Bool(bool),
-    // Holds index to string in chunk memory.
-    Str(Rc<String>),
+    Str(ObjRef),
+    Function(ObjRef),
+    Closure(ObjRef),
+    Struct(ObjRef),
+    Instance(ObjRef),
+    BoundMethod(ObjRef),
+    Native(ObjRef),
+    // Only created by the host, scripts can pass them around but have no syntax for them
+    List(ObjRef),
+    Map(ObjRef),
+    UserData(ObjRef),
 }
 
 impl Value {
-    pub fn from_str(s: &str) -> Self {
-        Self::Str(Rc::new(s.to_string()))
+    // The heap object this value refers to, if any.
+    pub fn as_obj(&self) -> Option<ObjRef> {
+        match *self {
+            Self::Str(r) | Self::Function(r) | Self::Closure(r) |
+            Self::Struct(r) | Self::Instance(r) | Self::BoundMethod(r) | Self::Native(r) |
+            Self::List(r) | Self::Map(r) | Self::UserData(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Self::Nil => "Nil",
+            Self::Number(_) => "Number",
+            Self::Bool(_) => "Bool",
+            Self::Str(_) => "Str",
+            Self::Function(_) | Self::Closure(_) | Self::BoundMethod(_) | Self::Native(_) => "Function",
+            Self::Struct(_) => "Struct",
+            Self::Instance(_) => "Instance",
+            Self::List(_) => "List",
+            Self::Map(_) => "Map",
+            Self::UserData(_) => "UserData",
+        }
     }
 
-    pub fn add(self, rhs: Value) -> Result<Value, String> {
+    // nil and false are falsey, every other value is truthy.
+    pub fn is_falsey(&self) -> bool {
+        matches!(self, Self::Nil | Self::Bool(false))
+    }
+
+    pub fn add(self, rhs: Value) -> Result<Value, RuntimeErrorKind> {
         let value = match &self {
             Self::Nil => Some(Self::Nil),
             Self::Number(l) => if let Self::Number(r) = rhs {
                 Some(Self::Number(l + r))
             } else { None }
-            Self::Str(l) => if let Self::Str(r) = &rhs {
-                Some(Self::Str(Rc::new(l.as_ref().clone() + r.as_ref())))
-            } else { None }
             _ => None,
         };
 
         if let Some(v) = value {
             Ok(v)
-        } else { Err("type mismatch or invalid '+' operation.".to_string()) }
+        } else { Err(RuntimeErrorKind::InvalidOperands { op: "+", left: self.type_name(), right: rhs.type_name() }) }
     }
 
-    pub fn sub(self, rhs: Value) -> Result<Value, String> {
+    pub fn sub(self, rhs: Value) -> Result<Value, RuntimeErrorKind> {
         let value = match self {
             Self::Nil => Some(Self::Nil),
             Self::Number(l) => if let Self::Number(r) = rhs {
@@ -42,10 +204,10 @@ impl Value {
 
         if let Some(v) = value {
             Ok(v)
-        } else { Err("type mismatch or invalid '-' operation.".to_string()) }
+        } else { Err(RuntimeErrorKind::InvalidOperands { op: "-", left: self.type_name(), right: rhs.type_name() }) }
     }
 
-    pub fn mul(self, rhs: Value) -> Result<Value, String> {
+    pub fn mul(self, rhs: Value) -> Result<Value, RuntimeErrorKind> {
         let value = match self {
             Self::Nil => Some(Self::Nil),
             Self::Number(l) => if let Self::Number(r) = rhs {
@@ -56,10 +218,10 @@ impl Value {
 
         if let Some(v) = value {
             Ok(v)
-        } else { Err("type mismatch or invalid '*' operation.".to_string()) }
+        } else { Err(RuntimeErrorKind::InvalidOperands { op: "*", left: self.type_name(), right: rhs.type_name() }) }
     }
 
-    pub fn div(self, rhs: Value) -> Result<Value, String> {
+    pub fn div(self, rhs: Value) -> Result<Value, RuntimeErrorKind> {
         let value = match self {
             Self::Nil => Some(Self::Nil),
             Self::Number(l) => if let Self::Number(r) = rhs {
@@ -70,24 +232,20 @@ impl Value {
 
         if let Some(v) = value {
             Ok(v)
-        } else { Err("type mismatch or invalid '/' operation.".to_string()) }
+        } else { Err(RuntimeErrorKind::InvalidOperands { op: "/", left: self.type_name(), right: rhs.type_name() }) }
     }
 
     pub fn unary(self, op: char) -> Option<Value> {
-        match self {
-            Self::Bool(b) => match op {
-                '!' => Some(Self::Bool(!b)),
-                _   => None,
-            }
-            Self::Number(n) => match op {
-                '-' => Some(Self::Number(-n)),
-                _   => None,
-            }
+        match op {
+            '!' => Some(Self::Bool(self.is_falsey())),
+            '-' => if let Self::Number(n) = self {
+                Some(Self::Number(-n))
+            } else { None }
             _ => None,
         }
     }
 
-    pub fn compare(self, rhs: Value, op: &str) -> Result<Value, String> {
+    pub fn compare(self, rhs: Value, op: &'static str) -> Result<Value, RuntimeErrorKind> {
         let value = match self {
             Self::Number(l) => if let Self::Number(r) = rhs {
                 Some(Self::Bool(match op {
@@ -103,41 +261,38 @@ impl Value {
         
         if let Some(v) = value {
             Ok(v)
-        } else { Err(format!("only numerical types are comparable, near {}", op)) }
+        } else { Err(RuntimeErrorKind::NotComparable { op, left: self.type_name(), right: rhs.type_name() }) }
     }
 
-    pub fn and(self, rhs: Value) -> Result<Value, String> {
-        let value = match self {
-            Self::Bool(l) => if let Self::Bool(r) = rhs {
-                Some(Self::Bool(l && r))
-            } else { None }
-            _ => None,
-        };
-        
-        if let Some(v) = value {
-            Ok(v)
-        } else { Err("only boolean values can be used for 'and' operation.".to_string())}
-    }
-
-    pub fn or(self, rhs: Value) -> Result<Value, String> {
-        let value = match self {
-            Self::Bool(l) => if let Self::Bool(r) = rhs {
-                Some(Self::Bool(l || r))
-            } else { None }
-            _ => None,
-        };
-        
-        if let Some(v) = value {
-            Ok(v)
-        } else { Err("only boolean values can be used for 'and' operation.".to_string())}
-    }
-
-    pub fn print(self) {
-        match self {
-            Self::Bool(b) => print!("{}", b),
-            Self::Number(n) => print!("{}", n),
-            Self::Str(s) => print!("{}", s),
-            Self::Nil => print!("nil"),
+    pub fn display(&self, heap: &Heap) -> String {
+        match *self {
+            Self::Bool(b) => b.to_string(),
+            Self::Number(n) => n.to_string(),
+            Self::Str(s) => heap.string(s).to_string(),
+            Self::Function(f) => heap.function(f).display(),
+            Self::Closure(c) => heap.function(heap.closure(c).function).display(),
+            Self::Struct(s) => heap.string(heap.structure(s).name).to_string(),
+            Self::Instance(i) => format!("{} instance", heap.string(heap.structure(heap.instance(i).structure).name)),
+            Self::BoundMethod(b) => match heap.get(heap.bound_method(b).method) {
+                Obj::Native(_) => Value::Native(heap.bound_method(b).method).display(heap),
+                _ => Value::Closure(heap.bound_method(b).method).display(heap),
+            }
+            Self::UserData(u) => format!("<{} userdata>", heap.userdata(u).type_name()),
+            Self::Native(n) => format!("<native fn {}>", heap.native(n).name),
+            Self::List(l) => {
+                let items: Vec<String> = heap.list(l).iter().map(|v| v.display(heap)).collect();
+                format!("[{}]", items.join(", "))
+            }
+            Self::Map(m) => {
+                // Sorted by key, so maps display the same every time
+                let mut entries: Vec<(&str, String)> = heap.map(m).iter()
+                    .map(|(&k, v)| (heap.string(k), v.display(heap)))
+                    .collect();
+                entries.sort();
+                let entries: Vec<String> = entries.into_iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+                format!("{{{}}}", entries.join(", "))
+            }
+            Self::Nil => "nil".to_string(),
         }
     }
 }
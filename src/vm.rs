@@ -1,7 +1,12 @@
-use std::{collections::{HashMap, VecDeque}, vec::Vec};
+use std::{any::{Any, TypeId}, cell::RefCell, collections::{HashMap, VecDeque}, io::{self, BufRead, Write}, rc::Rc, vec::Vec};
 
 use crate::{
-    compiler, util::KeyedArray, value::Value,
+    compiler,
+    error::{Error, RuntimeErrorKind, Span},
+    heap::{Heap, Obj, ObjRef},
+    util::KeyedArray,
+    convert::{FromValue, IntoNative},
+    value::{BoundMethod, Closure, Function, Getter, Instance, Native, NativeFn, Setter, Struct, Upvalue, UserData, UserDataCell, Value},
 };
 
 
@@ -11,10 +16,32 @@ pub enum Op {
     DefineGlobal(usize),
     GetGlobal(usize),
     SetGlobal(usize),
+    GetLocal(usize),
+    SetLocal(usize),
+    GetUpvalue(usize),
+    SetUpvalue(usize),
+    CloseUpvalue,
     Pop,
     True,
     False,
     Nil,
+
+    Jump(usize),
+    JumpIfFalse(usize),
+    Loop(usize),
+    Call(usize),
+    Invoke(usize, usize), // Calls a method by name constant, with an argument count
+    Closure(usize),
+
+    Struct(usize),
+    Inherit,
+    Field(usize),
+    InitField(usize), // Stores a field default's value into the new instance, returning from the default
+    Method(usize),
+    GetProperty(usize),
+    SetProperty(usize),
+    GetSuper(usize),
+    SuperInvoke(usize, usize),
     
     Not,
     Negate,
@@ -25,13 +52,12 @@ pub enum Op {
     GreaterEq,
     LessThan,
     LessEq,
-    And,
-    Or,
 
     Add,
     Sub,
     Mul,
     Div,
+    ToStr, // Converts the top of the stack to its displayed string
    
     Print,
     Return,
@@ -44,19 +70,22 @@ pub enum StaticMem {
 
 #[derive(Clone)]
 pub struct Chunk {
-    pub code: Vec<(Op, usize)>,
+    pub code: Vec<Op>,
+    // Run-length encoded source spans, each run starts at an op index and covers the ops up to the next run.
+    spans: Vec<(usize, Span)>,
     constants: KeyedArray<Value>,
 }
 
 impl std::fmt::Debug for Chunk {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "\n")?;
-        for (op, line) in &self.code {
-            if let Op::LoadConst(idx) = op {
-                write!(f, "[{:04}] - {:?} - {:?}\n", line, op, self.constants[*idx])?;
-            } else {
-                write!(f, "[{:04}] - {:?}\n", line, op)?;
+        writeln!(f)?;
+        for (i, op) in self.code.iter().enumerate() {
+            let span = self.span_at(i);
+            write!(f, "[{:04}:{:03}] - {:?}", span.line, span.column, op)?;
+            if let Op::LoadConst(idx) | Op::Closure(idx) = op {
+                write!(f, " - {:?}", self.constants[*idx])?;
             }
+            writeln!(f)?;
         }
         Ok(())
     }
@@ -68,55 +97,315 @@ impl Chunk {
     pub fn new() -> Self {
         Self {
             code: Vec::new(),
+            spans: Vec::new(),
             constants: KeyedArray::new(Self::SIZE),
         }
     }
     
+    pub fn constants(&self) -> impl Iterator<Item = &Value> {
+        self.constants.iter().map(|(_, value)| value)
+    }
+
+    // Number of run-length encoded span runs.
+    pub fn span_runs(&self) -> usize {
+        self.spans.len()
+    }
+
     pub fn add_constant(&mut self, value: Value) -> usize {
         self.constants.push(value) 
     }
 
-    pub fn push_constant(&mut self, value: Value, line: usize) -> &mut Self {
+    pub fn push_constant(&mut self, value: Value, span: Span) -> &mut Self {
         let idx = self.add_constant(value);
+        self.push_operation(Op::LoadConst(idx), span)
+    }
 
-        self.code.push((Op::LoadConst(idx), line));
+    pub fn push_operation(&mut self, op: Op, span: Span) -> &mut Self {
+        if self.spans.last().is_none_or(|(_, last)| *last != span) {
+            self.spans.push((self.code.len(), span));
+        }
+        self.code.push(op);
         self
     }
 
-    pub fn push_operation(&mut self, op: Op, line: usize) -> &mut Self {
-        self.code.push((op, line));
-        self
+    // Pushes a forward jump with a placeholder offset, returning its index for patch_jump.
+    pub fn push_jump(&mut self, op: Op, span: Span) -> usize {
+        self.push_operation(op, span);
+        self.code.len() - 1
+    }
+
+    // Pushes a backwards jump to the op at start.
+    pub fn push_loop(&mut self, start: usize, span: Span) -> &mut Self {
+        let offset = self.code.len() - start + 1;
+        self.push_operation(Op::Loop(offset), span)
+    }
+
+    // The source span the op at idx was compiled from.
+    pub fn span_at(&self, idx: usize) -> Span {
+        let run = self.spans.partition_point(|(start, _)| *start <= idx);
+        self.spans.get(run.wrapping_sub(1)).map_or(Span::default(), |(_, span)| *span)
+    }
+
+    // Points the jump at idx to the next op to be pushed.
+    pub fn patch_jump(&mut self, idx: usize) {
+        let target = self.code.len() - idx - 1;
+        match &mut self.code[idx] {
+            Op::Jump(offset) | Op::JumpIfFalse(offset) => *offset = target,
+            op => panic!("Attempted to patch non-jump operation {:?}", op),
+        }
     }
 }
 
+// An active function call.
+struct CallFrame {
+    closure:  ObjRef,
+    function: ObjRef, // The closure's function, kept here to save a lookup per op
+    ip:    usize,
+    slots: usize, // Stack index of the frame's slot 0
+}
+
+// Methods and properties registered for a UserData type, keyed by interned name.
+#[derive(Default)]
+struct UserDataType {
+    methods: HashMap<ObjRef, ObjRef>, // Native functions taking the object as args[0]
+    getters: HashMap<ObjRef, Getter>,
+    setters: HashMap<ObjRef, Setter>,
+}
+
 pub struct VM {
+    heap: Heap,
     stack: VecDeque<Value>,
-    chunk: Option<Chunk>,
-    globals: HashMap<String, Value>,
-    line: usize,
+    frames: Vec<CallFrame>,
+    globals: HashMap<ObjRef, Value>, // Keyed by interned name
+    init_string: ObjRef,
+    // Upvalues still pointing into the stack, ordered by stack index.
+    open_upvalues: Vec<ObjRef>,
+    userdata_types: HashMap<TypeId, UserDataType>,
+    // Where print writes and host code reads lines from, stdout and stdin unless swapped.
+    output: Box<dyn Write>,
+    input:  Box<dyn BufRead>,
 }
 
 impl VM {
+    const FRAMES_MAX: usize = 256;
+
     pub fn new() -> Self {
+        Self::with_io(io::stdout(), io::BufReader::new(io::stdin()))
+    }
+
+    pub fn with_io(output: impl Write + 'static, input: impl BufRead + 'static) -> Self {
+        let mut heap = Heap::new();
+        let init_string = heap.alloc_str("init");
         Self {
+            heap,
             stack: VecDeque::new(),
-            chunk: None,
+            frames: Vec::new(),
             globals: HashMap::new(),
-            line: 0,
+            init_string,
+            open_upvalues: Vec::new(),
+            userdata_types: HashMap::new(),
+            output: Box::new(output),
+            input:  Box::new(input),
         }
     }
     
-    pub fn interpret(&mut self, src: &str) -> Result<Value, String> {
-        self.chunk = Some(compiler::compile(src)?);
-        match self.execute_loaded_chunk() {
-            Ok(v)  => Ok(v),
-            Err(e) => Err(format!("Runtime error, at line {}: {}", self.line, e))
+    // Compiles and runs src, failing with every compile error or the runtime error that stopped it.
+    // Returns the value of the expression ending the script, or nil. It isn't rooted, so read it before running more code.
+    pub fn interpret(&mut self, src: &str) -> Result<Value, Vec<Error>> {
+        let chunk = compiler::compile(src, &mut self.heap)?;
+        self.load_chunk(chunk);
+        self.execute_loaded_chunk().map_err(|e| vec![e])
+    }
+
+    // Defines a global function implemented in Rust, called from scripts like any other function.
+    pub fn register_native(&mut self, name: &str, arity: usize, function: impl Fn(&mut VM, &[Value]) -> Result<Value, Error> + 'static) {
+        self.define_native(name, arity, Rc::new(function));
+    }
+
+    // Defines a global function from a Rust closure taking and returning convertible types,
+    // e.g. |a: f64, b: f64| a.hypot(b). Arguments that don't convert fail with the expected type.
+    pub fn register_fn<Args>(&mut self, name: &str, function: impl IntoNative<Args>) {
+        let arity = function.arity();
+        let function = function.into_native(name);
+        self.define_native(name, arity, function);
+    }
+
+    fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let key = self.intern(name.to_string());
+        // The name isn't rooted yet, so allocate without collecting
+        let native = self.heap.alloc(Obj::Native(Native { name: name.to_string(), arity, function }));
+        self.globals.insert(key, Value::Native(native));
+    }
+
+    // Hands a host object to scripts. It is finalized and dropped once they stop referencing it.
+    pub fn new_userdata(&mut self, data: impl UserData) -> Value {
+        // Values the data holds aren't rooted until it is on the heap, so allocate without collecting
+        Value::UserData(self.heap.alloc(Obj::UserData(UserDataCell(Rc::new(RefCell::new(data))))))
+    }
+
+    // Runs f on the host object value refers to, if it is a T.
+    // Panics if the object is already borrowed, e.g. by a method accessing its own receiver twice.
+    pub fn with_userdata<T: UserData, R>(&self, value: Value, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        let Value::UserData(r) = value else { return None };
+        let cell = self.heap.userdata(r).0.clone();
+        let mut data = cell.borrow_mut();
+        (&mut *data as &mut dyn Any).downcast_mut::<T>().map(f)
+    }
+
+    // Adds a method to every T, called as obj.name(...) with the object as args[0] and arity counting the rest.
+    pub fn register_method<T: UserData>(&mut self, name: &str, arity: usize, function: impl Fn(&mut VM, &[Value]) -> Result<Value, Error> + 'static) {
+        let key = self.intern(name.to_string());
+        // Neither is rooted until registered, so allocate without collecting
+        let native = self.heap.alloc(Obj::Native(Native { name: name.to_string(), arity, function: Rc::new(function) }));
+        self.userdata_types.entry(TypeId::of::<T>()).or_default().methods.insert(key, native);
+    }
+
+    // Adds a property read as obj.name to every T.
+    pub fn register_getter<T: UserData>(&mut self, name: &str, getter: impl Fn(&mut VM, Value) -> Result<Value, Error> + 'static) {
+        let key = self.intern(name.to_string());
+        self.userdata_types.entry(TypeId::of::<T>()).or_default().getters.insert(key, Rc::new(getter));
+    }
+
+    // Adds a property assigned as obj.name = value to every T.
+    pub fn register_setter<T: UserData>(&mut self, name: &str, setter: impl Fn(&mut VM, Value, Value) -> Result<(), Error> + 'static) {
+        let key = self.intern(name.to_string());
+        self.userdata_types.entry(TypeId::of::<T>()).or_default().setters.insert(key, Rc::new(setter));
+    }
+
+    fn userdata_type(&self, userdata: ObjRef) -> Option<&UserDataType> {
+        let data = self.heap.userdata(userdata).0.borrow();
+        self.userdata_types.get(&(&*data as &dyn Any).type_id())
+    }
+
+    // Interns s as a string value.
+    pub fn alloc_str(&mut self, s: &str) -> Value {
+        Value::Str(self.intern(s.to_string()))
+    }
+
+    // Roots a value on the stack while more objects are allocated, see alloc_from_roots.
+    pub(crate) fn root(&mut self, value: Value) {
+        self.stack.push_back(value);
+    }
+
+    pub(crate) fn roots(&self) -> usize {
+        self.stack.len()
+    }
+
+    // Allocates an object built from the values rooted since base, which are unrooted once it is built.
+    pub(crate) fn alloc_from_roots(&mut self, base: usize, build: impl FnOnce(Vec<Value>) -> Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        let values = self.stack.drain(base..).collect();
+        self.heap.alloc(build(values))
+    }
+
+    // Runs src and converts its result, e.g. vm.eval::<f64>("width * 2") for a config value.
+    pub fn eval<T: for<'a> FromValue<'a>>(&mut self, src: &str) -> Result<T, Vec<Error>> {
+        let value = self.interpret(src)?;
+        T::from_value(value, &self.heap).map_err(|kind| vec![Error::Runtime { kind, span: Span::default() }])
+    }
+
+    pub fn get_global(&self, name: &str) -> Option<&Value> {
+        self.globals.get(&self.heap.find_string(name)?)
+    }
+
+    // Swaps where print writes to, returning the previous output.
+    pub fn set_output(&mut self, output: impl Write + 'static) -> Box<dyn Write> {
+        std::mem::replace(&mut self.output, Box::new(output))
+    }
+
+    // Swaps where lines are read from, returning the previous input.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) -> Box<dyn BufRead> {
+        std::mem::replace(&mut self.input, Box::new(input))
+    }
+
+    pub fn output(&mut self) -> &mut dyn Write {
+        &mut self.output
+    }
+
+    // Reads the next line from the input, with its line ending. None once the input is exhausted.
+    pub fn read_line(&mut self) -> io::Result<Option<String>> {
+        let mut line = String::new();
+        match self.input.read_line(&mut line)? {
+            0 => Ok(None),
+            _ => Ok(Some(line)),
         }
     }
 
-    fn binary_op(op: Op, lhs: Value, rhs: Value) -> Result<Value, String> {
+    // Defines or overwrites a global, e.g. to hand scripts a host object.
+    pub fn set_global(&mut self, name: &str, value: Value) {
+        // Interning can collect, so keep the value rooted until it is stored
+        self.root(value);
+        let key = self.intern(name.to_string());
+        self.stack.pop_back();
+        self.globals.insert(key, value);
+    }
+
+    pub fn heap(&self) -> &Heap {
+        &self.heap
+    }
+
+    // Collect garbage before every allocation, for testing that every live object is reachable from a root.
+    pub fn set_stress_gc(&mut self, stress: bool) {
+        self.heap.stress = stress;
+    }
+
+    // Marks the roots, then frees every object they can't reach.
+    // Compiled constants are reachable from the script closure, which is on the stack while it runs.
+    pub fn collect_garbage(&mut self) {
+        for value in &self.stack {
+            self.heap.mark_value(*value);
+        }
+        for (name, value) in &self.globals {
+            self.heap.mark_obj(*name);
+            self.heap.mark_value(*value);
+        }
+        self.heap.mark_obj(self.init_string);
+        for frame in &self.frames {
+            self.heap.mark_obj(frame.closure);
+        }
+        for upvalue in &self.open_upvalues {
+            self.heap.mark_obj(*upvalue);
+        }
+        for userdata in self.userdata_types.values() {
+            for (name, method) in &userdata.methods {
+                self.heap.mark_obj(*name);
+                self.heap.mark_obj(*method);
+            }
+            for name in userdata.getters.keys().chain(userdata.setters.keys()) {
+                self.heap.mark_obj(*name);
+            }
+        }
+        self.heap.collect();
+    }
+
+    // Allocates an object, collecting first if the heap has grown enough.
+    // Anything obj references must already be reachable from a root.
+    fn alloc(&mut self, obj: Obj) -> ObjRef {
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.alloc(obj)
+    }
+
+    // Returns the interned copy of s, allocating it if this is the first use.
+    fn intern(&mut self, s: String) -> ObjRef {
+        if let Some(r) = self.heap.find_string(&s) {
+            return r;
+        }
+        if self.heap.should_collect() {
+            self.collect_garbage();
+        }
+        self.heap.intern(s)
+    }
+
+    fn binary_op(&mut self, op: Op, lhs: Value, rhs: Value) -> Result<Value, RuntimeErrorKind> {
         match op {
-            Op::Add => lhs.add(rhs),
+            Op::Add => if let (Value::Str(l), Value::Str(r)) = (lhs, rhs) {
+                let s = self.heap.string(l).to_string() + self.heap.string(r);
+                Ok(Value::Str(self.intern(s)))
+            } else { lhs.add(rhs) }
             Op::Sub => lhs.sub(rhs),
             Op::Mul => lhs.mul(rhs),
             Op::Div => lhs.div(rhs),
@@ -127,9 +416,7 @@ impl VM {
             Op::GreaterEq   => lhs.compare(rhs, ">="),
             Op::LessThan    => lhs.compare(rhs, "<"),
             Op::LessEq      => lhs.compare(rhs, "<="),
-            Op::And         => lhs.and(rhs),
-            Op::Or          => lhs.or(rhs),
-            _ => Err("invalid binary operation.".to_string()),
+            _ => unreachable!("invalid binary operation {:?}.", op),
         }
     }
 
@@ -141,81 +428,484 @@ impl VM {
         }
     }
     
+    // Wraps chunk in a script closure and sets up a call frame to run it.
     pub fn load_chunk(&mut self, chunk: Chunk) {
-        self.chunk = Some(chunk);
+        // The chunk's constants aren't rooted until the closure is on the stack, so don't collect here.
+        let function = self.heap.alloc(Obj::Function(Function { chunk, ..Function::new(None) }));
+        let closure = self.heap.alloc(Obj::Closure(Closure { function, upvalues: Vec::new() }));
+        self.stack.push_back(Value::Closure(closure));
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - 1,
+        });
+    }
+
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().expect("Expected a call frame.")
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().expect("Expected a call frame.")
+    }
+
+    fn read_constant(&self, idx: usize) -> Value {
+        self.heap.function(self.frame().function).chunk.constants[idx]
+    }
+
+    fn read_name(&self, idx: usize) -> ObjRef {
+        match self.read_constant(idx) {
+            Value::Str(name) => name,
+            _ => panic!("Expected a name constant."),
+        }
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - distance - 1]
+    }
+
+    fn call_value(&mut self, callee: Value, argc: usize) -> Result<(), RuntimeErrorKind> {
+        let callee_slot = self.stack.len() - argc - 1;
+        match callee {
+            Value::Closure(closure) => self.call(closure, argc),
+            Value::BoundMethod(bound) => {
+                let BoundMethod { receiver, method } = *self.heap.bound_method(bound);
+                self.stack[callee_slot] = receiver;
+                match self.heap.get(method) {
+                    Obj::Native(_) => self.call_native(method, argc, true),
+                    _ => self.call(method, argc),
+                }
+            }
+            Value::Native(native) => self.call_native(native, argc, false),
+            Value::Struct(structure) => {
+                let init = self.heap.structure(structure).methods.get(&self.init_string).copied();
+                // The struct stays rooted in the callee slot while the instance is allocated.
+                // Fields start out nil, their defaults are run once the instance is on the stack.
+                let defaults = self.heap.structure(structure).fields.clone();
+                let fields = defaults.iter().map(|&(name, _)| (name, Value::Nil)).collect();
+                let instance = self.alloc(Obj::Instance(Instance { structure, fields }));
+                self.stack[callee_slot] = Value::Instance(instance);
+                if let Some(init) = init {
+                    self.call(init, argc)?;
+                } else if argc != 0 {
+                    return Err(RuntimeErrorKind::ArityMismatch { expected: 0, got: argc });
+                }
+
+                // Defaults run before init, each in a frame of its own with the instance as receiver.
+                // The last frame pushed runs first, so they are pushed in reverse.
+                for default in defaults.into_iter().rev().filter_map(|(_, default)| default) {
+                    self.stack.push_back(Value::Instance(instance));
+                    self.call(default, 0)?;
+                }
+                Ok(())
+            }
+            _ => Err(RuntimeErrorKind::NotCallable),
+        }
+    }
+
+    // Calls a native with the arguments on top of the stack, and with the callee slot first if it is a method's receiver.
+    fn call_native(&mut self, native: ObjRef, argc: usize, receiver: bool) -> Result<(), RuntimeErrorKind> {
+        let Native { arity, ref function, .. } = *self.heap.native(native);
+        let function = function.clone();
+        if argc != arity {
+            return Err(RuntimeErrorKind::ArityMismatch { expected: arity, got: argc });
+        }
+        // The arguments stay on the stack during the call, keeping them rooted.
+        let callee_slot = self.stack.len() - argc - 1;
+        let first = if receiver { callee_slot } else { callee_slot + 1 };
+        let args: Vec<Value> = self.stack.range(first..).copied().collect();
+        let result = function(self, &args).map_err(Self::native_error)?;
+        self.stack.truncate(callee_slot);
+        self.stack.push_back(result);
+        Ok(())
+    }
+
+    // Errors from host code are reported at the script's call, whatever span they carry.
+    fn native_error(error: Error) -> RuntimeErrorKind {
+        match error {
+            Error::Runtime { kind, .. } => kind,
+            e => RuntimeErrorKind::Native(e.to_string()),
+        }
+    }
+
+    // The object must be rooted by the caller, as getters and binding a method allocate.
+    fn userdata_property(&mut self, userdata: ObjRef, name: ObjRef) -> Result<Value, RuntimeErrorKind> {
+        let userdata_type = self.userdata_type(userdata);
+        if let Some(getter) = userdata_type.and_then(|t| t.getters.get(&name)).cloned() {
+            return getter(self, Value::UserData(userdata)).map_err(Self::native_error);
+        }
+        match userdata_type.and_then(|t| t.methods.get(&name)).copied() {
+            Some(method) => Ok(Value::BoundMethod(self.alloc(Obj::BoundMethod(BoundMethod {
+                receiver: Value::UserData(userdata),
+                method,
+            })))),
+            None => Err(RuntimeErrorKind::UndefinedProperty(self.heap.string(name).to_string())),
+        }
+    }
+
+    fn invoke(&mut self, name: ObjRef, argc: usize) -> Result<(), RuntimeErrorKind> {
+        let instance = match self.peek(argc) {
+            Value::Instance(instance) => instance,
+            Value::UserData(userdata) => {
+                if let Some(method) = self.userdata_type(userdata).and_then(|t| t.methods.get(&name)).copied() {
+                    return self.call_native(method, argc, true);
+                }
+                // Otherwise call what a getter returns, like a field holding a function.
+                let callee = self.userdata_property(userdata, name)?;
+                let callee_slot = self.stack.len() - argc - 1;
+                self.stack[callee_slot] = callee;
+                return self.call_value(callee, argc);
+            }
+            _ => return Err(RuntimeErrorKind::NotAnInstance("methods")),
+        };
+
+        // A field holding a function shadows a method of the same name.
+        if let Some(&field) = self.heap.instance(instance).fields.get(&name) {
+            let callee_slot = self.stack.len() - argc - 1;
+            self.stack[callee_slot] = field;
+            return self.call_value(field, argc);
+        }
+
+        let structure = self.heap.instance(instance).structure;
+        self.invoke_from_struct(structure, name, argc)
+    }
+
+    fn invoke_from_struct(&mut self, structure: ObjRef, name: ObjRef, argc: usize) -> Result<(), RuntimeErrorKind> {
+        match self.heap.structure(structure).methods.get(&name).copied() {
+            Some(method) => self.call(method, argc),
+            None => Err(RuntimeErrorKind::UndefinedProperty(self.heap.string(name).to_string())),
+        }
+    }
+
+    // The receiver must be rooted by the caller, as binding allocates.
+    fn bind_method(&mut self, structure: ObjRef, name: ObjRef, receiver: Value) -> Result<Value, RuntimeErrorKind> {
+        match self.heap.structure(structure).methods.get(&name).copied() {
+            Some(method) => Ok(Value::BoundMethod(self.alloc(Obj::BoundMethod(BoundMethod {
+                receiver,
+                method,
+            })))),
+            None => Err(RuntimeErrorKind::UndefinedProperty(self.heap.string(name).to_string())),
+        }
+    }
+
+    fn call(&mut self, closure: ObjRef, argc: usize) -> Result<(), RuntimeErrorKind> {
+        let function = self.heap.closure(closure).function;
+        let arity = self.heap.function(function).arity;
+        if argc != arity {
+            return Err(RuntimeErrorKind::ArityMismatch { expected: arity, got: argc });
+        }
+        if self.frames.len() >= Self::FRAMES_MAX {
+            return Err(RuntimeErrorKind::StackOverflow);
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - argc - 1,
+        });
+        Ok(())
+    }
+
+    fn open_slot(&self, upvalue: ObjRef) -> usize {
+        match self.heap.upvalue(upvalue) {
+            Upvalue::Open(slot) => *slot,
+            Upvalue::Closed(_) => panic!("Expected an open upvalue."),
+        }
+    }
+
+    // Returns the open upvalue for the stack slot, creating it if this is the first capture.
+    fn capture_upvalue(&mut self, slot: usize) -> ObjRef {
+        let mut insert_at = self.open_upvalues.len();
+        for (i, &upvalue) in self.open_upvalues.iter().enumerate() {
+            let open = self.open_slot(upvalue);
+            if open == slot { return upvalue }
+            if open > slot { insert_at = i; break }
+        }
+
+        let upvalue = self.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.insert(insert_at, upvalue);
+        upvalue
+    }
+
+    // Moves every captured variable at or above last off the stack and into its upvalue.
+    fn close_upvalues(&mut self, last: usize) {
+        while let Some(&upvalue) = self.open_upvalues.last() {
+            let slot = self.open_slot(upvalue);
+            if slot < last { break }
+
+            *self.heap.upvalue_mut(upvalue) = Upvalue::Closed(self.stack[slot]);
+            self.open_upvalues.pop();
+        }
     }
     
-    pub fn execute_loaded_chunk(&mut self) -> Result<Value, String> {
-        if self.chunk.is_none() { return Err("no chunk has been loaded.".to_string()); }
-        
-        if let Some(chunk) = &mut self.chunk {
-            for (op, line) in &chunk.code {
-                self.line = *line;
-                match op {
-                    // Push
-                    Op::LoadConst(idx) => {
-                        let value = chunk.constants[*idx].clone();
-                        self.stack.push_back(value);
-                    }
-                    Op::DefineGlobal(idx) => {
-                        let global = chunk.constants[*idx].clone();
-                        if let Value::Str(name) = global {
-                            self.globals.insert(name.to_string(), self.stack.pop_back().expect("Expected item on the stack."));
-                        }
+    // Runs the loaded chunk to completion, resetting the VM if it fails so the next chunk starts on an empty stack.
+    pub fn execute_loaded_chunk(&mut self) -> Result<Value, Error> {
+        self.run().map_err(|kind| {
+            // The failed op is the one before ip in the innermost frame.
+            let span = self.frames.last().map_or(Span::default(), |frame| {
+                self.heap.function(frame.function).chunk.span_at(frame.ip.saturating_sub(1))
+            });
+            self.stack.clear();
+            self.frames.clear();
+            self.open_upvalues.clear();
+            Error::Runtime { kind, span }
+        })
+    }
+
+    fn run(&mut self) -> Result<Value, RuntimeErrorKind> {
+        if self.frames.is_empty() { return Err(RuntimeErrorKind::NoChunkLoaded); }
+
+        loop {
+            let frame = self.frame();
+            let Some(&op) = self.heap.function(frame.function).chunk.code.get(frame.ip) else {
+                // Ran off the end of the script
+                let frame = self.frames.pop().unwrap();
+                self.close_upvalues(frame.slots);
+                self.stack.truncate(frame.slots);
+                return Ok(Value::Nil);
+            };
+            self.frame_mut().ip += 1;
+
+            match op {
+                // Push
+                Op::LoadConst(idx) => {
+                    let value = self.read_constant(idx);
+                    self.stack.push_back(value);
+                }
+                Op::DefineGlobal(idx) => {
+                    let name = self.read_name(idx);
+                    self.globals.insert(name, self.stack.pop_back().expect("Expected item on the stack."));
+                }
+                Op::GetGlobal(idx) => {
+                    let name = self.read_name(idx);
+                    if let Some(value) = self.globals.get(&name) {
+                        self.stack.push_back(*value);
+                    } else { return Err(RuntimeErrorKind::UndefinedVariable(self.heap.string(name).to_string())) }
+                }
+                Op::SetGlobal(idx) => {
+                    let name = self.read_name(idx);
+                    if let Some(value) = self.globals.get_mut(&name) {
+                        *value = *self.stack.back().expect("Expected item on the stack.");
+                    } else { return Err(RuntimeErrorKind::UndefinedVariable(self.heap.string(name).to_string())) }
+                }
+                Op::GetLocal(slot) => {
+                    let value = self.stack[self.frame().slots + slot];
+                    self.stack.push_back(value);
+                }
+                Op::SetLocal(slot) => {
+                    let slot = self.frame().slots + slot;
+                    self.stack[slot] = *self.stack.back().expect("Expected item on the stack.");
+                }
+                Op::GetUpvalue(idx) => {
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[idx];
+                    let value = match self.heap.upvalue(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot],
+                        Upvalue::Closed(value) => *value,
+                    };
+                    self.stack.push_back(value);
+                }
+                Op::SetUpvalue(idx) => {
+                    let value = *self.stack.back().expect("Expected item on the stack.");
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[idx];
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(slot) => self.stack[*slot] = value,
+                        Upvalue::Closed(closed) => *closed = value,
                     }
-                    Op::GetGlobal(idx) => {
-                        let global = chunk.constants[*idx].clone();
-                        if let Value::Str(name) = global &&
-                           let Some(value) = self.globals.get(name.as_ref())
-                        {
-                            self.stack.push_back(value.clone());
-                        } else { return Err("undefined variable.".to_string()) }
+                }
+                Op::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop_back();
+                }
+                Op::Pop => { self.stack.pop_back().expect("Expected item on the stack."); },
+                Op::True => self.stack.push_back(Value::Bool(true)),
+                Op::False => self.stack.push_back(Value::Bool(false)),
+                Op::Nil => self.stack.push_back(Value::Nil),
+
+                // Control flow
+                Op::Jump(offset) => self.frame_mut().ip += offset,
+                Op::Loop(offset) => self.frame_mut().ip -= offset,
+                Op::JumpIfFalse(offset) => {
+                    if self.stack.back().expect("Expected item on the stack.").is_falsey() {
+                        self.frame_mut().ip += offset;
                     }
-                    Op::SetGlobal(idx) => {
-                        let global = chunk.constants[*idx].clone();
-                        if let Value::Str(name) = global && let Some(value) = self.globals.get_mut(name.as_ref())
-                        {
-                            *value = self.stack.back().expect("Expected item on the stack.").clone();
-                        } else { return Err("undefined variable.".to_string()) }
+                }
+                Op::Call(argc) => {
+                    self.call_value(self.peek(argc), argc)?;
+                }
+                Op::Invoke(idx, argc) => {
+                    let name = self.read_name(idx);
+                    self.invoke(name, argc)?;
+                }
+                Op::Closure(idx) => {
+                    let Value::Function(function) = self.read_constant(idx) else {
+                        panic!("Expected a function constant.");
+                    };
+                    let slots = self.frame().slots;
+                    let enclosing = self.frame().closure;
+                    // Captured upvalues are rooted by open_upvalues or the enclosing closure while the rest are created.
+                    let upvalues = self.heap.function(function).upvalues.clone().iter()
+                        .map(|desc| if desc.is_local {
+                            self.capture_upvalue(slots + desc.index)
+                        } else {
+                            self.heap.closure(enclosing).upvalues[desc.index]
+                        })
+                        .collect();
+                    let closure = self.alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.stack.push_back(Value::Closure(closure));
+                }
 
+                // Structs
+                Op::Struct(idx) => {
+                    let name = self.read_name(idx);
+                    let structure = self.alloc(Obj::Struct(Struct::new(name)));
+                    self.stack.push_back(Value::Struct(structure));
+                }
+                Op::Inherit => {
+                    let Value::Struct(parent) = self.peek(1) else {
+                        return Err(RuntimeErrorKind::ParentNotStruct);
+                    };
+                    if let Value::Struct(child) = self.peek(0) {
+                        let parent = self.heap.structure(parent).clone();
+                        let child = self.heap.structure_mut(child);
+                        child.fields.extend(parent.fields);
+                        child.methods.extend(parent.methods);
                     }
-                    Op::Pop => { self.stack.pop_back().expect("Expected item on the stack."); },
-                    Op::True => self.stack.push_back(Value::Bool(true)),
-                    Op::False => self.stack.push_back(Value::Bool(false)),
-                    Op::Nil => self.stack.push_back(Value::Nil),
-
-                    // Binary
-                    Op::Add     | Op::Sub       | Op::Mul | Op::Div |
-                    Op::Equal   | Op::NotEqual  |
-                    Op::GreaterThan | Op::GreaterEq |
-                    Op::LessThan    | Op::LessEq    | 
-                    Op::And | Op::Or => {
-                        let rhs = self.stack.pop_back().expect("Expected item on the stack.");
-                        let lhs = self.stack.pop_back().expect("Expected item on the stack.");
-                        self.stack.push_back(Self::binary_op(*op, lhs, rhs)?);
+                    self.stack.pop_back();
+                }
+                Op::Field(idx) => {
+                    let name = self.read_name(idx);
+                    let default = match self.stack.pop_back().expect("Expected item on the stack.") {
+                        Value::Closure(default) => Some(default),
+                        _ => None,
+                    };
+                    if let Value::Struct(structure) = self.peek(0) {
+                        // Redeclaring a field replaces its default
+                        let fields = &mut self.heap.structure_mut(structure).fields;
+                        match fields.iter_mut().find(|(field, _)| *field == name) {
+                            Some(field) => field.1 = default,
+                            None => fields.push((name, default)),
+                        }
                     }
-                    // Unary
-                    Op::Negate | Op::Not => {
-                        let v = self.stack.pop_back().expect("Expected item on the stack.");
-                        if let Some(v) = Self::unary_op(*op, v) {
-                            self.stack.push_back(v);
-                        } else { return Err("type mismatch on unary operation.".to_string()); }
+                }
+                Op::InitField(idx) => {
+                    let name = self.read_name(idx);
+                    let value = self.stack.pop_back().expect("Expected item on the stack.");
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    if let Value::Instance(instance) = self.stack[frame.slots] {
+                        self.heap.instance_mut(instance).fields.insert(name, value);
                     }
-                    Op::Print => {
-                        let v = self.stack.pop_back().expect("Expected item on the stack.");
-                        v.print();
-                        print!("\n");
+                    self.stack.truncate(frame.slots);
+                }
+                Op::Method(idx) => {
+                    let name = self.read_name(idx);
+                    let method = self.stack.pop_back().expect("Expected item on the stack.");
+                    if let Value::Struct(structure) = self.peek(0) && let Value::Closure(method) = method {
+                        self.heap.structure_mut(structure).methods.insert(name, method);
                     }
-                    Op::Return => {
-                        // Temporary return behaviour
-                        return Ok(self.stack.pop_back().unwrap_or(Value::Nil));
+                }
+                Op::GetProperty(idx) => {
+                    let name = self.read_name(idx);
+                    // The instance stays on the stack until the property is found, as binding a method allocates.
+                    let value = match self.peek(0) {
+                        Value::Instance(instance) => match self.heap.instance(instance).fields.get(&name).copied() {
+                            Some(field) => field,
+                            None => {
+                                let structure = self.heap.instance(instance).structure;
+                                self.bind_method(structure, name, Value::Instance(instance))?
+                            }
+                        }
+                        Value::UserData(userdata) => self.userdata_property(userdata, name)?,
+                        _ => return Err(RuntimeErrorKind::NotAnInstance("properties")),
+                    };
+                    self.stack.pop_back();
+                    self.stack.push_back(value);
+                }
+                Op::SetProperty(idx) => {
+                    let name = self.read_name(idx);
+                    // Both stay on the stack until the value is set, as setters can allocate.
+                    let value = self.peek(0);
+                    match self.peek(1) {
+                        Value::Instance(instance) => match self.heap.instance_mut(instance).fields.get_mut(&name) {
+                            Some(field) => *field = value,
+                            None => return Err(RuntimeErrorKind::UndefinedField(self.heap.string(name).to_string())),
+                        }
+                        Value::UserData(userdata) => {
+                            let setter = self.userdata_type(userdata).and_then(|t| t.setters.get(&name)).cloned();
+                            let Some(setter) = setter else {
+                                return Err(RuntimeErrorKind::UndefinedField(self.heap.string(name).to_string()));
+                            };
+                            setter(self, Value::UserData(userdata), value).map_err(Self::native_error)?;
+                        }
+                        _ => return Err(RuntimeErrorKind::NotAnInstance("fields")),
                     }
+                    self.stack.pop_back();
+                    self.stack.pop_back();
+                    self.stack.push_back(value);
+                }
+
+                Op::GetSuper(idx) => {
+                    let name = self.read_name(idx);
+                    let Value::Struct(parent) = self.peek(0) else {
+                        return Err(RuntimeErrorKind::ParentNotStruct);
+                    };
+                    let bound = self.bind_method(parent, name, self.peek(1))?;
+                    self.stack.pop_back();
+                    self.stack.pop_back();
+                    self.stack.push_back(bound);
+                }
+                Op::SuperInvoke(idx, argc) => {
+                    let name = self.read_name(idx);
+                    let Value::Struct(parent) = self.stack.pop_back().expect("Expected item on the stack.") else {
+                        return Err(RuntimeErrorKind::ParentNotStruct);
+                    };
+                    self.invoke_from_struct(parent, name, argc)?;
+                }
+
+                // Binary
+                Op::Add     | Op::Sub       | Op::Mul | Op::Div |
+                Op::Equal   | Op::NotEqual  |
+                Op::GreaterThan | Op::GreaterEq |
+                Op::LessThan    | Op::LessEq => {
+                    let rhs = self.stack.pop_back().expect("Expected item on the stack.");
+                    let lhs = self.stack.pop_back().expect("Expected item on the stack.");
+                    let result = self.binary_op(op, lhs, rhs)?;
+                    self.stack.push_back(result);
+                }
+                Op::ToStr => {
+                    // Leave the value on the stack while interning so it stays rooted
+                    let v = self.peek(0);
+                    if !matches!(v, Value::Str(_)) {
+                        let s = v.display(&self.heap);
+                        let r = self.intern(s);
+                        *self.stack.back_mut().unwrap() = Value::Str(r);
+                    }
+                }
+                // Unary
+                Op::Negate | Op::Not => {
+                    let v = self.stack.pop_back().expect("Expected item on the stack.");
+                    if let Some(result) = Self::unary_op(op, v) {
+                        self.stack.push_back(result);
+                    } else { return Err(RuntimeErrorKind::InvalidOperand { op: "-", operand: v.type_name() }); }
+                }
+                Op::Print => {
+                    let v = self.stack.pop_back().expect("Expected item on the stack.");
+                    writeln!(self.output, "{}", v.display(&self.heap)).map_err(|e| RuntimeErrorKind::Output(e.to_string()))?;
+                }
+                Op::Return => {
+                    let result = self.stack.pop_back().unwrap_or(Value::Nil);
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    self.stack.truncate(frame.slots);
+
+                    if self.frames.is_empty() {
+                        return Ok(result);
+                    }
+                    self.stack.push_back(result);
                 }
             }
         }
-
-        self.chunk = None;
-        
-        return Ok(Value::Nil);
     }
 }